@@ -1,6 +1,16 @@
+use crate::account::*;
 use crate::context::*;
 use crate::error::*;
 use anchor_lang::prelude::*;
+use spl_governance::state::realm;
+
+pub fn registrar_is_migrated(registrar: &AccountLoader<Registrar>) -> Result<()> {
+    require!(
+        registrar.load()?.version == REGISTRAR_VERSION,
+        RegistrarNotMigrated
+    );
+    Ok(())
+}
 
 pub fn rate_is_empty(ctx: &Context<CreateExchangeRate>, idx: u16) -> Result<()> {
     let r = &ctx.accounts.registrar.load()?;
@@ -8,3 +18,22 @@ pub fn rate_is_empty(ctx: &Context<CreateExchangeRate>, idx: u16) -> Result<()>
     require!(r.rates[idx as usize].rate == 0, RateNotZero);
     Ok(())
 }
+
+pub fn realm_is_valid(ctx: &Context<CreateRegistrar>) -> Result<()> {
+    require!(
+        ctx.accounts.governance_program_id.key() == crate::governance_program::ID,
+        InvalidGovernanceProgram
+    );
+    let realm_data =
+        realm::get_realm_data(ctx.accounts.governance_program_id.key, &ctx.accounts.realm)?;
+    let mint = ctx.accounts.realm_governing_token_mint.key();
+    require!(
+        realm_data.community_mint == mint || realm_data.config.council_mint == Some(mint),
+        InvalidRealmGoverningTokenMint
+    );
+    require!(
+        realm_data.authority == Some(ctx.accounts.realm_authority.key()),
+        InvalidRealmAuthority
+    );
+    Ok(())
+}
@@ -1,8 +1,12 @@
 use crate::error::*;
-use anchor_lang::__private::bytemuck::Zeroable;
+use crate::oracle::PriceFeed;
+use anchor_lang::__private::bytemuck::{self, Pod, Zeroable};
 use anchor_lang::prelude::*;
+use anchor_lang::Discriminator;
 use anchor_spl::vote_weight_record;
+use spl_governance::addins::voter_weight::VoterWeightAction;
 use std::convert::TryFrom;
+use std::mem::size_of;
 
 // Generate a VoteWeightRecord Anchor wrapper, owned by the current program.
 // VoteWeightRecords are unique in that they are defined by the SPL governance
@@ -15,17 +19,90 @@ pub const SECS_PER_DAY: i64 = 86_400;
 /// Maximum number of days one can lock for.
 pub const MAX_DAYS_LOCKED: u64 = 2555;
 
+/// Maximum `Registrar::lockup_end_alignment`, so that aligned lockups start
+/// at most a day after their tokens are deposited.
+pub const MAX_LOCKUP_END_ALIGNMENT: u32 = SECS_PER_DAY as u32;
+
+/// Denominator of `ExchangeRateEntry::max_boost_pct`.
+pub const BOOST_PCT_DENOMINATOR: u64 = 100;
+
+/// Denominator of `VoterWeightCap::max_vote_weight_bps`.
+pub const BPS_DENOMINATOR: u16 = 10_000;
+
+/// Current layout version of `Registrar` accounts.
+pub const REGISTRAR_VERSION: u8 = 7;
+
+/// Current layout version of `Voter` accounts.
+pub const VOTER_VERSION: u8 = 3;
+
+/// Number of deposit entries new voters have room for. See `grow_voter`.
+pub const VOTER_INITIAL_CAPACITY: u8 = 4;
+
+/// Maximum number of deposit entries a voter can have room for.
+pub const VOTER_MAX_CAPACITY: u8 = 32;
+
+// `Voter` data up to its deposits.
+const VOTER_DEPOSITS_OFFSET: usize =
+    size_of::<Voter>() - VOTER_MAX_CAPACITY as usize * size_of::<DepositEntry>();
+
+// `Voter` data up to and including `voter_weight_record_bump`, which is where
+// the deposits started before version 3.
+const VOTER_LEGACY_HEADER_LEN: usize = 1 + 32 * 2 + 2;
+
+/// Size of the data, after the discriminator, of registrars created before
+/// accounts were versioned: `authority`, `realm`, `realm_community_mint`,
+/// `bump`, two `{ mint, rate, decimals }` exchange rates and `rate_decimals`.
+pub const UNVERSIONED_REGISTRAR_LEN: usize = 32 * 3 + 1 + 2 * UNVERSIONED_EXCHANGE_RATE_LEN + 1;
+
+// Size of an exchange rate of an unversioned registrar.
+const UNVERSIONED_EXCHANGE_RATE_LEN: usize = 32 + 8 + 1;
+
+/// Size of the data, after the discriminator, of voters created before
+/// accounts were versioned: `authority`, `registrar`, `voter_bump`,
+/// `voter_weight_record_bump` and 32 deposit entries, which kept their
+/// layout.
+pub const UNVERSIONED_VOTER_LEN: usize = 32 * 2 + 2 + 32 * size_of::<DepositEntry>();
+
 /// Instance of a voting rights distributor.
 #[account(zero_copy)]
 pub struct Registrar {
+    // Layout version, kept first so it can be read from any version.
+    pub version: u8,
     pub authority: Pubkey,
     pub realm: Pubkey,
-    pub realm_community_mint: Pubkey,
+    // The realm's community or council mint this registrar produces voter
+    // weight for. Part of the registrar's PDA seeds.
+    pub realm_governing_token_mint: Pubkey,
     pub bump: u8,
     // The length should be adjusted for one's use case.
     pub rates: [ExchangeRateEntry; 2],
     // The decimals to use when converting deposits into a common currency.
     pub rate_decimals: u8,
+    // Deposit filters used when computing the voter weight, indexed by
+    // `WeightAction`.
+    pub weight_policies: [WeightPolicy; 5],
+    // Whether deposits issue frozen voting tokens.
+    pub voting_token_mode: VotingTokenMode,
+    // Running totals of all deposits, indexed like `rates`. Added in
+    // version 2.
+    pub deposit_totals: [DepositTotals; 2],
+    // How the max vote weight is computed. Added in version 3.
+    pub max_vote_weight_mode: MaxVoteWeightMode,
+    // Emergency switches set by the authority. Added in version 4.
+    pub pause_flags: PauseFlags,
+    // New lockups are shifted forward to end on a multiple of this many
+    // seconds since the unix epoch, e.g. 86_400 for UTC midnight. Zero
+    // disables the alignment. Added in version 5.
+    pub lockup_end_alignment: u32,
+    // How deposit voting powers are combined into a voter weight. Added in
+    // version 6.
+    pub weight_aggregation: WeightAggregation,
+    // Limits the voter weight of any single voter. Added in version 7.
+    pub voter_weight_cap: VoterWeightCap,
+    // The result of the last `update_max_vote_weight`. Added in version 7.
+    pub max_vote_weight: u64,
+    // Empty bytes for fields added by future versions.
+    pub reserved: [u8; 53],
 }
 
 impl Registrar {
@@ -34,35 +111,636 @@ impl Registrar {
     ///
     /// The "common regsitrar currency" is the unit used to calculate voting
     /// weight.
+    ///
+    /// The exchange rate is the fraction `rate / rate_denominator`. Mints with
+    /// more decimals than the registrar are scaled down, and the result is
+    /// rounded down.
     pub fn convert(&self, er: &ExchangeRateEntry, amount: u64) -> Result<u64> {
-        require!(self.rate_decimals >= er.decimals, InvalidDecimals);
-        let decimal_diff = self.rate_decimals.checked_sub(er.decimals).unwrap();
-        let convert = amount
-            .checked_mul(er.rate)
-            .unwrap()
-            .checked_mul(10u64.pow(decimal_diff.into()))
-            .unwrap();
-        Ok(convert)
+        let (decimal_mul, decimal_div) = if self.rate_decimals >= er.decimals {
+            (decimal_scale(self.rate_decimals - er.decimals)?, 1)
+        } else {
+            (1, decimal_scale(er.decimals - self.rate_decimals)?)
+        };
+        let convert = u128::from(amount)
+            .checked_mul(er.rate.into())
+            .ok_or(ErrorCode::UnableToConvert)?
+            .checked_mul(decimal_mul)
+            .ok_or(ErrorCode::UnableToConvert)?
+            .checked_div(
+                u128::from(er.rate_denominator)
+                    .checked_mul(decimal_div)
+                    .ok_or(ErrorCode::UnableToConvert)?,
+            )
+            .ok_or(ErrorCode::UnableToConvert)?;
+        u64::try_from(convert).map_err(|_| ErrorCode::UnableToConvert.into())
+    }
+
+    /// Upgrades the account to `REGISTRAR_VERSION`.
+    ///
+    /// Each version's upgrade step initializes the fields it adds. Since
+    /// programs can't reallocate accounts on the runtime this targets, new
+    /// fields must be carved out of `reserved`.
+    pub fn migrate(&mut self) -> Result<()> {
+        require!(self.version < REGISTRAR_VERSION, AccountUpToDate);
+        if self.version < 2 {
+            // Totals only track deposits and withdrawals made after the
+            // upgrade.
+            self.deposit_totals = [DepositTotals::default(); 2];
+        }
+        if self.version < 3 {
+            // Boosted rates need a mode that accounts for the boost.
+            self.max_vote_weight_mode = MaxVoteWeightMode::Supply;
+            if !self.rates.iter().all(|er| self.is_valid_boost(er)) {
+                self.max_vote_weight_mode = MaxVoteWeightMode::LockedSupply;
+            }
+        }
+        if self.version < 4 {
+            self.pause_flags = PauseFlags::default();
+        }
+        if self.version < 5 {
+            self.lockup_end_alignment = 0;
+        }
+        if self.version < 6 {
+            self.weight_aggregation = WeightAggregation::Linear;
+        }
+        if self.version < 7 {
+            self.voter_weight_cap = VoterWeightCap::default();
+            self.max_vote_weight = 0;
+        }
+        self.version = REGISTRAR_VERSION;
+        Ok(())
+    }
+
+    /// Reads a registrar created before accounts were versioned, given its
+    /// account data, as version 0 and upgrades it to `REGISTRAR_VERSION`.
+    ///
+    /// Such accounts are smaller than the current layout, so they can't be
+    /// upgraded in place and are copied into a new account instead.
+    pub fn from_unversioned(data: &[u8]) -> Result<Self> {
+        require!(
+            data.len() == 8 + UNVERSIONED_REGISTRAR_LEN && data[..8] == Registrar::discriminator(),
+            InvalidUnversionedAccount
+        );
+        let data = &data[8..];
+        let mut registrar = Registrar::zeroed();
+        registrar.authority = Pubkey::new(&data[..32]);
+        registrar.realm = Pubkey::new(&data[32..64]);
+        registrar.realm_governing_token_mint = Pubkey::new(&data[64..96]);
+        registrar.bump = data[96];
+        let rates = data[97..UNVERSIONED_REGISTRAR_LEN - 1].chunks(UNVERSIONED_EXCHANGE_RATE_LEN);
+        for (er, old) in registrar.rates.iter_mut().zip(rates) {
+            let mut rate = [0u8; 8];
+            rate.copy_from_slice(&old[32..40]);
+            let rate = u64::from_le_bytes(rate);
+            if rate == 0 {
+                // Empty entry.
+                continue;
+            }
+            // Rates were whole numbers, and all mints had the default lockup
+            // and boost.
+            *er = ExchangeRateEntry {
+                mint: Pubkey::new(&old[..32]),
+                rate,
+                rate_denominator: 1,
+                decimals: old[40],
+                max_lockup_days: MAX_DAYS_LOCKED,
+                max_boost_pct: BOOST_PCT_DENOMINATOR,
+                ..ExchangeRateEntry::default()
+            };
+        }
+        registrar.rate_decimals = data[UNVERSIONED_REGISTRAR_LEN - 1];
+        // Deposits issued a voting token per mint, and counted towards every
+        // action.
+        registrar.voting_token_mode = VotingTokenMode::PerMint;
+        registrar.weight_policies = [WeightPolicy::default(); 5];
+        registrar.migrate()?;
+        Ok(registrar)
+    }
+
+    /// Errors unless `mints` holds exactly one mint per exchange rate in use,
+    /// in the order of `rates`.
+    pub fn require_rate_mints(&self, mints: &[Pubkey]) -> Result<()> {
+        for (idx, mint) in mints.iter().enumerate() {
+            require!(!mints[..idx].contains(mint), DuplicateMintAccount);
+            require!(
+                self.rates.iter().any(|er| er.rate > 0 && er.mint == *mint),
+                ExchangeRateEntryNotFound
+            );
+        }
+        let mut expected = self.rates.iter().filter(|er| er.rate > 0);
+        for mint in mints {
+            // All mints are distinct and registered, so there's at most one
+            // per exchange rate.
+            let er = expected.next().unwrap();
+            require!(er.mint == *mint, MintAccountOutOfOrder);
+        }
+        require!(expected.next().is_none(), MissingMintAccount);
+        Ok(())
+    }
+
+    /// Returns the contribution of `amount` native tokens of the exchange
+    /// rate's mint to the max vote weight.
+    pub fn max_voting_power(&self, er: &ExchangeRateEntry, amount: u64) -> Result<u64> {
+        let amount_scaled = self.convert(er, amount)?;
+        match self.max_vote_weight_mode {
+            MaxVoteWeightMode::Supply => Ok(amount_scaled),
+            // The voting power of the amount locked for the max lockup period.
+            MaxVoteWeightMode::LockedSupply | MaxVoteWeightMode::DepositTotals => {
+                let boosted = u128::from(amount_scaled)
+                    .checked_mul(er.max_boost_pct.into())
+                    .ok_or(ErrorCode::UnableToConvert)?
+                    .checked_div(BOOST_PCT_DENOMINATOR.into())
+                    .ok_or(ErrorCode::UnableToConvert)?;
+                u64::try_from(boosted).map_err(|_| ErrorCode::UnableToConvert.into())
+            }
+        }
+    }
+
+    /// Returns the index of the deposit's exchange rate, erroring unless the
+    /// deposit is in use and `mint` is the rate's mint.
+    pub fn deposit_rate_idx(&self, d: &DepositEntry, mint: &Pubkey) -> Result<usize> {
+        require!(d.is_used, InvalidDepositId);
+        let er_idx = d.rate_idx as usize;
+        if er_idx >= self.rates.len() || self.rates[er_idx].mint != *mint {
+            msg!("mint {} doesn't match the deposit's exchange rate", mint);
+            return Err(ErrorCode::InvalidDepositMint.into());
+        }
+        Ok(er_idx)
+    }
+
+    /// Returns true if no deposit of the exchange rate's mint can have more
+    /// voting power than the mint contributes to the max vote weight, which
+    /// `MaxVoteWeightMode::Supply` doesn't boost.
+    pub fn is_valid_boost(&self, er: &ExchangeRateEntry) -> bool {
+        self.max_vote_weight_mode != MaxVoteWeightMode::Supply
+            || er.max_boost_pct <= BOOST_PCT_DENOMINATOR
+    }
+
+    /// Returns a copy of the deposit with its scaled amount at the current
+    /// exchange rate, which only changes for oracle-backed rates.
+    pub fn rescale(&self, d: &DepositEntry, curr_slot: u64) -> Result<DepositEntry> {
+        let er = self.rates[d.rate_idx as usize];
+        let mut d = *d;
+        if er.is_oracle() {
+            er.require_fresh(curr_slot)?;
+            d.amount_scaled = self.convert(&er, d.amount_deposited)?;
+        }
+        Ok(d)
+    }
+
+    /// Returns the start and end of a lockup of `lockup_secs` created at
+    /// `curr_ts`. Both are shifted forward by the same amount so that the
+    /// lockup ends on a multiple of `lockup_end_alignment`. Lockups without
+    /// a duration aren't shifted, so their tokens stay unlocked.
+    ///
+    /// The shifted end can't be more than `max_lockup_secs` after `curr_ts`.
+    pub fn lockup_bounds(
+        &self,
+        curr_ts: i64,
+        lockup_secs: i64,
+        max_lockup_secs: i64,
+    ) -> Result<(i64, i64)> {
+        let end_ts = curr_ts
+            .checked_add(lockup_secs)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        let period = i64::from(self.lockup_end_alignment);
+        if period == 0 || lockup_secs == 0 {
+            return Ok((curr_ts, end_ts));
+        }
+        let shift = (period - end_ts.rem_euclid(period)) % period;
+        let shifted = |ts: i64| ts.checked_add(shift).ok_or(ErrorCode::ArithmeticOverflow);
+        let (start_ts, end_ts) = (shifted(curr_ts)?, shifted(end_ts)?);
+        if end_ts - curr_ts > max_lockup_secs {
+            msg!(
+                "aligned lockup ends {} seconds from now, more than {}",
+                end_ts - curr_ts,
+                max_lockup_secs
+            );
+            return Err(ErrorCode::InvalidDays.into());
+        }
+        Ok((start_ts, end_ts))
+    }
+
+    /// Applies the registrar's `WeightAggregation` to the summed voting power
+    /// of a voter's deposits.
+    pub fn aggregate_weight(&self, weight: u64) -> Result<u64> {
+        match self.weight_aggregation {
+            WeightAggregation::Linear => Ok(weight),
+            WeightAggregation::Quadratic => {
+                // Scale up before taking the root, so that the result keeps
+                // `rate_decimals` decimals.
+                let scaled = u128::from(weight)
+                    .checked_mul(decimal_scale(self.rate_decimals)?)
+                    .ok_or(ErrorCode::UnableToConvert)?;
+                u64::try_from(isqrt(scaled)).map_err(|_| ErrorCode::UnableToConvert.into())
+            }
+        }
+    }
+
+    /// Limits `weight` by the registrar's `voter_weight_cap`.
+    pub fn cap_weight(&self, weight: u64) -> Result<u64> {
+        let cap = self.voter_weight_cap;
+        let mut capped = weight;
+        if cap.max_weight > 0 {
+            capped = capped.min(cap.max_weight);
+        }
+        if cap.max_vote_weight_bps > 0 {
+            require!(self.max_vote_weight > 0, MaxVoteWeightNotComputed);
+            let max = u128::from(self.max_vote_weight) * u128::from(cap.max_vote_weight_bps)
+                / u128::from(BPS_DENOMINATOR);
+            // Fits, since the fraction is at most one.
+            capped = capped.min(max as u64);
+        }
+        Ok(capped)
+    }
+
+    /// Returns the deposit filter used when computing voter weight for the
+    /// given action.
+    pub fn weight_policy(&self, action: WeightAction) -> WeightPolicy {
+        self.weight_policies[action as usize]
+    }
+}
+
+/// Returns `10^decimals`, for converting between decimal representations.
+fn decimal_scale(decimals: u8) -> Result<u128> {
+    10u128
+        .checked_pow(decimals.into())
+        .ok_or_else(|| ErrorCode::InvalidDecimals.into())
+}
+
+/// Returns the square root of `n`, rounded down.
+fn isqrt(n: u128) -> u128 {
+    if n < 2 {
+        return n;
+    }
+    // Newton's method, starting from a power of two above the root, from
+    // which it decreases monotonically towards the root.
+    let bits = 128 - n.leading_zeros();
+    let mut x = 1u128 << ((bits + 1) / 2);
+    loop {
+        let y = (x + n / x) / 2;
+        if y >= x {
+            return x;
+        }
+        x = y;
     }
 }
 
 /// User account for minting voting rights.
-#[account(zero_copy)]
+///
+/// Voter accounts only store the deposit entries they have room for, so
+/// they're read and written through `AccountDeserialize` and
+/// `AccountSerialize` rather than zero-copy.
+#[zero_copy]
 pub struct Voter {
+    // Layout version, kept first so it can be read from any version.
+    pub version: u8,
     pub authority: Pubkey,
     pub registrar: Pubkey,
     pub voter_bump: u8,
     pub voter_weight_record_bump: u8,
+    // Number of deposit entries stored in the account. Added in version 3.
+    pub capacity: u8,
+    // The voter weight before `Registrar::voter_weight_cap` applied, as of
+    // the last voter weight record update. Added in version 3.
+    pub last_uncapped_weight: u64,
+    // Empty bytes for fields added by future versions.
+    pub reserved: [u8; 55],
+    // Entries from `capacity` on aren't stored and are always unused. Kept
+    // last, so that the account holds a prefix of this struct.
     pub deposits: [DepositEntry; 32],
 }
 
+unsafe impl Pod for Voter {}
+unsafe impl Zeroable for Voter {}
+
+impl Discriminator for Voter {
+    // The discriminator of `#[account] Voter`, which the account used to be.
+    fn discriminator() -> [u8; 8] {
+        [241, 93, 35, 191, 254, 147, 17, 202]
+    }
+}
+
+impl Owner for Voter {
+    fn owner() -> Pubkey {
+        crate::ID
+    }
+}
+
+impl AccountDeserialize for Voter {
+    fn try_deserialize(buf: &mut &[u8]) -> Result<Self> {
+        require!(
+            buf.len() > 8 && buf[..8] == Voter::discriminator(),
+            InvalidVoterData
+        );
+        // Older layouts must be upgraded with `migrate_voter` first.
+        require!(buf[8] == VOTER_VERSION, VoterNotMigrated);
+        Self::try_deserialize_unchecked(buf)
+    }
+
+    fn try_deserialize_unchecked(buf: &mut &[u8]) -> Result<Self> {
+        let data = buf.get(8..).ok_or(ErrorCode::InvalidVoterData)?;
+        let capacity = data
+            .len()
+            .checked_sub(VOTER_DEPOSITS_OFFSET)
+            .map(|len| len / size_of::<DepositEntry>())
+            .filter(|&capacity| capacity <= VOTER_MAX_CAPACITY as usize)
+            .ok_or(ErrorCode::InvalidVoterData)?;
+        let mut voter = Voter::zeroed();
+        let len = Voter::space(capacity as u8) - 8;
+        bytemuck::bytes_of_mut(&mut voter)[..len].copy_from_slice(&data[..len]);
+        // Newly created accounts are all zeros.
+        voter.capacity = capacity as u8;
+        Ok(voter)
+    }
+}
+
+impl AccountSerialize for Voter {
+    fn try_serialize<W: std::io::Write>(&self, writer: &mut W) -> Result<()> {
+        let len = Voter::space(self.capacity) - 8;
+        writer
+            .write_all(&Voter::discriminator())
+            .and_then(|_| writer.write_all(&bytemuck::bytes_of(self)[..len]))
+            .map_err(|_| ErrorCode::InvalidVoterData.into())
+    }
+}
+
 impl Voter {
-    pub fn weight(&self) -> Result<u64> {
-        let curr_ts = Clock::get()?.unix_timestamp;
+    /// Returns the voting power of all deposits counting towards `action`.
+    pub fn weight(&self, registrar: &Registrar, action: WeightAction) -> Result<u64> {
+        let clock = Clock::get()?;
+        self.weight_at(registrar, action, clock.unix_timestamp, clock.slot)
+    }
+
+    /// Returns the voting power of all deposits counting towards `action` at
+    /// `curr_ts` and `curr_slot`.
+    pub fn weight_at(
+        &self,
+        registrar: &Registrar,
+        action: WeightAction,
+        curr_ts: i64,
+        curr_slot: u64,
+    ) -> Result<u64> {
+        let policy = registrar.weight_policy(action);
         self.deposits
             .iter()
             .filter(|d| d.is_used)
-            .try_fold(0, |sum, d| d.voting_power(curr_ts).map(|vp| sum + vp))
+            .try_fold(0u64, |sum, d| -> Result<u64> {
+                if !policy.includes(d, curr_ts)? {
+                    return Ok(sum);
+                }
+                let er = registrar.rates[d.rate_idx as usize];
+                let d = registrar.rescale(d, curr_slot)?;
+                let vp = d.voting_power(&er, curr_ts)?;
+                sum.checked_add(vp)
+                    .ok_or_else(|| ErrorCode::ArithmeticOverflow.into())
+            })
+    }
+
+    /// Returns the voter weight for `action` at `curr_ts` and `curr_slot`,
+    /// limited by the registrar's cap. The uncapped weight is kept in
+    /// `last_uncapped_weight`, so that capped voters can be told apart.
+    pub fn capped_weight_at(
+        &mut self,
+        registrar: &Registrar,
+        action: WeightAction,
+        curr_ts: i64,
+        curr_slot: u64,
+    ) -> Result<u64> {
+        let weight = self.weight_at(registrar, action, curr_ts, curr_slot)?;
+        self.last_uncapped_weight = registrar.aggregate_weight(weight)?;
+        registrar.cap_weight(self.last_uncapped_weight)
+    }
+
+    /// Writes the voter weight for `action` into the voter's `record`, valid
+    /// for the current slot and scoped to `action` and `target`.
+    pub fn update_weight_record(
+        &mut self,
+        registrar: &Registrar,
+        record: &mut VoterWeightRecord,
+        action: WeightAction,
+        target: Option<Pubkey>,
+    ) -> Result<()> {
+        let clock = Clock::get()?;
+        record.voter_weight =
+            self.capped_weight_at(registrar, action, clock.unix_timestamp, clock.slot)?;
+        let uncapped = self.last_uncapped_weight;
+        if record.voter_weight < uncapped {
+            msg!(
+                "voter weight {} capped to {}",
+                uncapped,
+                record.voter_weight
+            );
+        }
+        record.voter_weight_expiry = Some(clock.slot);
+        record.weight_action = Some(action.into());
+        record.weight_action_target = target;
+        Ok(())
+    }
+
+    /// Returns the size of a voter account with room for `capacity` deposit
+    /// entries.
+    pub fn space(capacity: u8) -> usize {
+        8 + VOTER_DEPOSITS_OFFSET + capacity as usize * size_of::<DepositEntry>()
+    }
+
+    /// Returns deposit entry `id`, which must be within the voter's capacity.
+    pub fn deposit_mut(&mut self, id: u8) -> Result<&mut DepositEntry> {
+        if id >= self.capacity {
+            msg!("deposit id {} out of range, capacity {}", id, self.capacity);
+            return Err(ErrorCode::InvalidDepositId.into());
+        }
+        Ok(&mut self.deposits[id as usize])
+    }
+
+    /// Returns deposit entry `id`, which must be within the voter's capacity
+    /// and in use.
+    pub fn used_deposit_mut(&mut self, id: u8) -> Result<&mut DepositEntry> {
+        let d = self.deposit_mut(id)?;
+        if !d.is_used {
+            msg!("deposit id {} unused", id);
+            return Err(ErrorCode::InvalidDepositId.into());
+        }
+        Ok(d)
+    }
+
+    /// Returns the index of the first unused deposit entry the account has
+    /// room for.
+    pub fn free_deposit_idx(&self) -> Option<usize> {
+        self.deposits[..self.capacity as usize]
+            .iter()
+            .position(|d| !d.is_used)
+    }
+
+    /// Reads a voter created before accounts were versioned. See
+    /// `Registrar::from_unversioned`.
+    pub fn from_unversioned(data: &[u8]) -> Result<Self> {
+        require!(
+            data.len() == 8 + UNVERSIONED_VOTER_LEN && data[..8] == Voter::discriminator(),
+            InvalidUnversionedAccount
+        );
+        // The header lacks the version byte.
+        let mut voter = Voter::from_legacy_parts(
+            &data[8..7 + VOTER_LEGACY_HEADER_LEN],
+            &data[7 + VOTER_LEGACY_HEADER_LEN..],
+        );
+        voter.migrate()?;
+        Ok(voter)
+    }
+
+    /// Reads a voter of any version from its account data, for `migrate`.
+    pub fn from_any_version(data: &[u8]) -> Result<Self> {
+        require!(
+            data.len() > 8 && data[..8] == Voter::discriminator(),
+            InvalidVoterData
+        );
+        if data[8] == VOTER_VERSION {
+            return Voter::try_deserialize_unchecked(&mut &data[..]);
+        }
+        // Before version 3, voters held all 32 deposits, followed by
+        // `reserved`.
+        let deposits_end =
+            8 + VOTER_LEGACY_HEADER_LEN + VOTER_MAX_CAPACITY as usize * size_of::<DepositEntry>();
+        require!(data.len() == 8 + size_of::<Voter>(), InvalidVoterData);
+        Ok(Voter::from_legacy_parts(
+            &data[8..8 + VOTER_LEGACY_HEADER_LEN],
+            &data[8 + VOTER_LEGACY_HEADER_LEN..deposits_end],
+        ))
+    }
+
+    // Builds a voter from the fields of a layout from before version 3,
+    // where `header` is the data up to `deposits`, without the version byte
+    // if it's missing.
+    fn from_legacy_parts(header: &[u8], deposits: &[u8]) -> Self {
+        let mut voter = Voter::zeroed();
+        let bytes = bytemuck::bytes_of_mut(&mut voter);
+        bytes[VOTER_LEGACY_HEADER_LEN - header.len()..VOTER_LEGACY_HEADER_LEN]
+            .copy_from_slice(header);
+        bytes[VOTER_DEPOSITS_OFFSET..].copy_from_slice(deposits);
+        voter
+    }
+
+    /// Upgrades the account to `VOTER_VERSION`. See `Registrar::migrate`.
+    pub fn migrate(&mut self) -> Result<()> {
+        require!(self.version < VOTER_VERSION, AccountUpToDate);
+        if self.version < 2 {
+            for d in self.deposits.iter_mut() {
+                d.lockup.granularity = LockupGranularity::Days;
+            }
+        }
+        if self.version < 3 {
+            self.capacity = VOTER_MAX_CAPACITY;
+        }
+        self.version = VOTER_VERSION;
+        Ok(())
+    }
+}
+
+/// Running totals over all deposits of a single mint.
+#[zero_copy]
+#[derive(Default)]
+pub struct DepositTotals {
+    // Amount ever deposited, in the native currency.
+    pub deposited: u64,
+    // Amount ever withdrawn, in the native currency.
+    pub withdrawn: u64,
+    // Sum of the scaled amounts of all deposits, each at the exchange rate of
+    // its last deposit or withdrawal.
+    pub amount_scaled: u64,
+}
+
+impl DepositTotals {
+    /// Records a deposit of `amount`, which changed the deposit's scaled
+    /// amount from `scaled_before` to `scaled_after`.
+    pub fn record_deposit(
+        &mut self,
+        amount: u64,
+        scaled_before: u64,
+        scaled_after: u64,
+    ) -> Result<()> {
+        self.deposited = self
+            .deposited
+            .checked_add(amount)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        self.rescale(scaled_before, scaled_after)
+    }
+
+    /// Records a withdrawal of `amount`, which changed the deposit's scaled
+    /// amount from `scaled_before` to `scaled_after`.
+    pub fn record_withdrawal(
+        &mut self,
+        amount: u64,
+        scaled_before: u64,
+        scaled_after: u64,
+    ) -> Result<()> {
+        self.withdrawn = self
+            .withdrawn
+            .checked_add(amount)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        self.rescale(scaled_before, scaled_after)
+    }
+
+    /// Returns the amount the exchange vault should hold for these deposits.
+    pub fn amount_held(&self) -> u64 {
+        // Saturates for registrars upgraded from version 1, which may see
+        // withdrawals of deposits made before totals were tracked.
+        self.deposited.saturating_sub(self.withdrawn)
+    }
+
+    fn rescale(&mut self, scaled_before: u64, scaled_after: u64) -> Result<()> {
+        self.amount_scaled = self
+            .amount_scaled
+            .saturating_sub(scaled_before)
+            .checked_add(scaled_after)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        Ok(())
+    }
+}
+
+/// Emergency switches halting parts of the program, e.g. while a bug in the
+/// exchange rate or vesting math is being fixed.
+#[zero_copy]
+#[derive(AnchorSerialize, AnchorDeserialize, Default)]
+pub struct PauseFlags {
+    // Halts `update_deposit`, and thus `create_deposit`, and `reset_lockup`.
+    pub deposits: bool,
+    // Halts `withdraw`.
+    pub withdrawals: bool,
+    // Halts `update_voter_weight_record`.
+    pub weight_updates: bool,
+}
+
+/// Limits the voter weight of any single voter. Fields that are zero don't
+/// limit the weight, and if both are set, the lower limit applies.
+#[zero_copy]
+#[derive(AnchorSerialize, AnchorDeserialize, Default)]
+pub struct VoterWeightCap {
+    // Maximum voter weight, in the common currency.
+    pub max_weight: u64,
+    // Maximum voter weight, in basis points of the registrar's
+    // `max_vote_weight`.
+    pub max_vote_weight_bps: u16,
+}
+
+/// Restricts which deposits count towards the voter weight for a given
+/// `WeightAction`. The default policy counts every deposit.
+#[zero_copy]
+#[derive(AnchorSerialize, AnchorDeserialize, Default)]
+pub struct WeightPolicy {
+    // Minimum number of days left on a lockup for the deposit to count.
+    pub min_lockup_days: u64,
+    // Bitmask over `Registrar::rates` indices whose deposits don't count.
+    pub excluded_rates: u8,
+}
+
+impl WeightPolicy {
+    /// Returns true if the deposit counts towards the voter weight.
+    pub fn includes(&self, d: &DepositEntry, curr_ts: i64) -> Result<bool> {
+        let rate_bit = 1u8.checked_shl(d.rate_idx.into()).unwrap_or(0);
+        if self.excluded_rates & rate_bit != 0 {
+            return Ok(false);
+        }
+        Ok(d.lockup.days_left(curr_ts)? >= self.min_lockup_days)
     }
 }
 
@@ -72,14 +750,82 @@ impl Voter {
 pub struct ExchangeRateEntry {
     // Mint for this entry.
     pub mint: Pubkey,
-    // Exchange rate into the common currency.
+    // Exchange rate into the common currency, as the fraction
+    // `rate / rate_denominator`.
     pub rate: u64,
+    pub rate_denominator: u64,
     // Mint decimals.
     pub decimals: u8,
+    // Lockup days at which deposits of this mint reach `max_boost_pct`.
+    pub max_lockup_days: u64,
+    // Voting power, in percent of the scaled amount, of a deposit locked for
+    // `max_lockup_days`.
+    pub max_boost_pct: u64,
+    // Price feed the rate is refreshed from. The default pubkey means the
+    // rate is fixed.
+    pub oracle: Pubkey,
+    // Bounds on rates accepted from the oracle.
+    pub min_rate: u64,
+    pub max_rate: u64,
+    // Number of slots after publication an oracle rate can be used for.
+    pub max_staleness_slots: u64,
+    // Slot at which the current oracle rate was published.
+    pub last_update_slot: u64,
 }
 
 unsafe impl Zeroable for ExchangeRateEntry {}
 
+impl ExchangeRateEntry {
+    /// Returns true if the lockup boost configuration is usable.
+    pub fn is_valid_lockup_config(&self) -> bool {
+        self.max_lockup_days > 0
+            && self.max_lockup_days <= MAX_DAYS_LOCKED
+            && self.max_boost_pct > 0
+    }
+
+    /// Returns true if the rate is read from a price feed.
+    pub fn is_oracle(&self) -> bool {
+        self.oracle != Pubkey::default()
+    }
+
+    /// Returns true if the oracle bounds are usable. Fixed rates are always
+    /// valid.
+    pub fn is_valid_oracle_config(&self) -> bool {
+        !self.is_oracle()
+            || (self.min_rate > 0 && self.min_rate <= self.rate && self.rate <= self.max_rate)
+    }
+
+    /// Errors if the rate is read from a price feed that hasn't been
+    /// refreshed within the staleness limit.
+    pub fn require_fresh(&self, curr_slot: u64) -> Result<()> {
+        if !self.is_oracle() {
+            return Ok(());
+        }
+        require!(
+            self.last_update_slot > 0
+                && curr_slot.saturating_sub(self.last_update_slot) <= self.max_staleness_slots,
+            StaleExchangeRate
+        );
+        Ok(())
+    }
+
+    /// Updates the rate from the given price feed, checking both its
+    /// staleness and the configured bounds.
+    pub fn update_from_feed(&mut self, feed: &PriceFeed, curr_slot: u64) -> Result<()> {
+        require!(
+            curr_slot.saturating_sub(feed.publish_slot) <= self.max_staleness_slots,
+            StaleExchangeRate
+        );
+        require!(
+            feed.rate >= self.min_rate && feed.rate <= self.max_rate,
+            ExchangeRateOutOfBounds
+        );
+        self.rate = feed.rate;
+        self.last_update_slot = feed.publish_slot;
+        Ok(())
+    }
+}
+
 /// Bookkeeping for a single deposit for a given mint and lockup schedule.
 #[zero_copy]
 pub struct DepositEntry {
@@ -113,6 +859,11 @@ impl DepositEntry {
     /// day lockup, which has 1/2555 the voting power of a 7 year lockup--
     /// assuming the amount locked up is equal.
     ///
+    /// The examples below use the default configuration. Each exchange rate
+    /// entry can use its own maximum lockup (in place of 2555) and multiply
+    /// the result by its own maximum boost, `max_boost_pct / 100`, so that
+    /// e.g. LP tokens can be boosted more than the governance token.
+    ///
     /// To achieve this with the SPL governance program--which requires a "max
     /// vote weight"--we attach what amounts to a scalar multiplier between 0
     /// and 1 to normalize voting power. This multiplier is a function of
@@ -188,68 +939,136 @@ impl DepositEntry {
     ///
     /// To calculate the decay, we can simply re-use the above sum, adjusting
     /// `n` for the number of days left in the lockup.
-    pub fn voting_power(&self, curr_ts: i64) -> Result<u64> {
-        if curr_ts < self.lockup.start_ts {
-            return Ok(0);
-        }
-        match self.lockup.kind {
-            LockupKind::Daily => self.voting_power_daily(curr_ts),
-            LockupKind::Cliff => self.voting_power_cliff(curr_ts),
+    ///
+    /// ## Second Granularity
+    ///
+    /// Lockups with `LockupGranularity::Seconds` use the same calculations
+    /// with seconds in place of days, so voting power decays every second
+    /// instead of in daily steps. For cliff lockups, that is
+    ///
+    /// ```
+    /// voting_power = (secs_left / (2555 * 86400)) * amount
+    /// ```
+    ///
+    /// For daily lockups, which then vest continuously, the sum becomes an
+    /// integral, and `(n * [n + 1]) / 2` turns into `n^2 / 2`:
+    ///
+    /// ```
+    /// voting_power = (secs_left / (2 * 2555 * 86400)) * amount
+    /// ```
+    ///
+    /// ## Aligned Lockups
+    ///
+    /// Lockups shifted forward by `Registrar::lockup_end_alignment` start
+    /// after their tokens are deposited, and are the only ones starting in
+    /// the future. Before any lockup starts, its tokens are locked, so its
+    /// voting power is the one at the start rather than zero.
+    pub fn voting_power(&self, er: &ExchangeRateEntry, curr_ts: i64) -> Result<u64> {
+        match (self.lockup.kind, self.lockup.granularity) {
+            (LockupKind::Daily, LockupGranularity::Days) => self.voting_power_daily(er, curr_ts),
+            (LockupKind::Cliff, LockupGranularity::Days) => self.voting_power_cliff(er, curr_ts),
+            (LockupKind::Daily, LockupGranularity::Seconds) => {
+                self.voting_power_secs(er, curr_ts, 2)
+            }
+            (LockupKind::Cliff, LockupGranularity::Seconds) => {
+                self.voting_power_secs(er, curr_ts, 1)
+            }
         }
     }
 
-    fn voting_power_daily(&self, curr_ts: i64) -> Result<u64> {
-        let m = MAX_DAYS_LOCKED;
-        let n = self.lockup.days_left(curr_ts)?;
+    // Voting power of second granularity lockups, where `divisor` is 2 for
+    // continuously vesting lockups and 1 for cliff lockups.
+    fn voting_power_secs(
+        &self,
+        er: &ExchangeRateEntry,
+        curr_ts: i64,
+        divisor: u128,
+    ) -> Result<u64> {
+        let max_secs = u128::from(er.max_lockup_days) * SECS_PER_DAY as u128;
+        let voting_power = u128::from(self.lockup.secs_left(curr_ts))
+            .checked_mul(self.amount_scaled.into())
+            .ok_or(ErrorCode::UnableToConvert)?
+            .checked_mul(er.max_boost_pct.into())
+            .ok_or(ErrorCode::UnableToConvert)?
+            .checked_div(
+                max_secs
+                    .checked_mul(divisor * u128::from(BOOST_PCT_DENOMINATOR))
+                    .ok_or(ErrorCode::UnableToConvert)?,
+            )
+            .ok_or(ErrorCode::UnableToConvert)?;
+
+        u64::try_from(voting_power).map_err(|_| ErrorCode::UnableToConvert.into())
+    }
+
+    fn voting_power_daily(&self, er: &ExchangeRateEntry, curr_ts: i64) -> Result<u64> {
+        let m = u128::from(er.max_lockup_days);
+        let n = u128::from(self.lockup.days_left(curr_ts)?);
 
         if n == 0 {
             return Ok(0);
         }
 
-        let decayed_vote_weight = self
-            .amount_scaled
+        let decayed_vote_weight = u128::from(self.amount_scaled)
             .checked_mul(
                 // Ok to divide by two here because, if n is zero, then the
                 // voting power is zero. And if n is one or above, then the
                 // numerator is 2 or above.
-                n.checked_mul(n.checked_add(1).unwrap())
-                    .unwrap()
+                n.checked_mul(n.checked_add(1).ok_or(ErrorCode::UnableToConvert)?)
+                    .ok_or(ErrorCode::UnableToConvert)?
                     .checked_div(2)
-                    .unwrap(),
+                    .ok_or(ErrorCode::UnableToConvert)?,
             )
-            .unwrap()
-            .checked_div(m.checked_mul(n).unwrap()) //.checked_mul(2).unwrap())
-            .unwrap();
+            .ok_or(ErrorCode::UnableToConvert)?
+            .checked_mul(er.max_boost_pct.into())
+            .ok_or(ErrorCode::UnableToConvert)?
+            .checked_div(
+                m.checked_mul(n)
+                    .ok_or(ErrorCode::UnableToConvert)?
+                    .checked_mul(BOOST_PCT_DENOMINATOR.into())
+                    .ok_or(ErrorCode::UnableToConvert)?,
+            )
+            .ok_or(ErrorCode::UnableToConvert)?;
 
-        Ok(decayed_vote_weight)
+        u64::try_from(decayed_vote_weight).map_err(|_| ErrorCode::UnableToConvert.into())
     }
 
-    fn voting_power_cliff(&self, curr_ts: i64) -> Result<u64> {
-        let decayed_voting_weight = self
-            .lockup
-            .days_left(curr_ts)?
-            .checked_mul(self.amount_scaled)
-            .unwrap()
-            .checked_div(MAX_DAYS_LOCKED)
-            .unwrap();
+    fn voting_power_cliff(&self, er: &ExchangeRateEntry, curr_ts: i64) -> Result<u64> {
+        let decayed_voting_weight = u128::from(self.lockup.days_left(curr_ts)?)
+            .checked_mul(self.amount_scaled.into())
+            .ok_or(ErrorCode::UnableToConvert)?
+            .checked_mul(er.max_boost_pct.into())
+            .ok_or(ErrorCode::UnableToConvert)?
+            .checked_div(
+                u128::from(er.max_lockup_days)
+                    .checked_mul(BOOST_PCT_DENOMINATOR.into())
+                    .ok_or(ErrorCode::UnableToConvert)?,
+            )
+            .ok_or(ErrorCode::UnableToConvert)?;
 
-        Ok(decayed_voting_weight)
+        u64::try_from(decayed_voting_weight).map_err(|_| ErrorCode::UnableToConvert.into())
     }
 
     /// Returns the amount of unlocked tokens for this deposit--in native units
     /// of the original token amount (not scaled by the exchange rate).
     pub fn vested(&self) -> Result<u64> {
-        let curr_ts = Clock::get()?.unix_timestamp;
+        self.vested_at(Clock::get()?.unix_timestamp)
+    }
+
+    /// Returns the amount of unlocked tokens for this deposit at `curr_ts`.
+    pub fn vested_at(&self, curr_ts: i64) -> Result<u64> {
         if curr_ts < self.lockup.start_ts {
             return Ok(0);
         }
         match self.lockup.kind {
             LockupKind::Daily => self.vested_daily(curr_ts),
-            LockupKind::Cliff => self.vested_cliff(),
+            LockupKind::Cliff => self.vested_cliff(curr_ts),
         }
     }
 
     fn vested_daily(&self, curr_ts: i64) -> Result<u64> {
+        if self.lockup.granularity == LockupGranularity::Seconds {
+            return self.vested_continuously(curr_ts);
+        }
         let day_current = self.lockup.day_current(curr_ts)?;
         let days_total = self.lockup.days_total()?;
         if day_current >= days_total {
@@ -258,25 +1077,128 @@ impl DepositEntry {
         let vested = self
             .amount_deposited
             .checked_mul(day_current)
-            .unwrap()
+            .ok_or(ErrorCode::UnableToConvert)?
             .checked_div(days_total)
-            .unwrap();
+            .ok_or(ErrorCode::UnableToConvert)?;
         Ok(vested)
     }
 
-    fn vested_cliff(&self) -> Result<u64> {
-        let curr_ts = Clock::get()?.unix_timestamp;
+    fn vested_continuously(&self, curr_ts: i64) -> Result<u64> {
+        let lockup_secs = self.lockup.end_ts.saturating_sub(self.lockup.start_ts);
+        let secs_left = self.lockup.secs_left(curr_ts);
+        if secs_left == 0 {
+            return Ok(self.amount_deposited);
+        }
+        let secs_elapsed = lockup_secs as u64 - secs_left;
+        let vested =
+            u128::from(self.amount_deposited) * u128::from(secs_elapsed) / lockup_secs as u128;
+        u64::try_from(vested).map_err(|_| ErrorCode::UnableToConvert.into())
+    }
+
+    fn vested_cliff(&self, curr_ts: i64) -> Result<u64> {
         if curr_ts < self.lockup.end_ts {
             return Ok(0);
         }
         Ok(self.amount_deposited)
     }
 
+    /// Moves the end of the lockup `days` further out, optionally turning a
+    /// `Daily` lockup into a `Cliff` one.
+    ///
+    /// Tokens vested at `curr_ts` stay vested: the extension is rejected if
+    /// it would lower the vested amount. The start of a partially vested
+    /// `Daily` lockup moves back, see `extension_start_shift`, so that its
+    /// vested amount is kept and the rest vests until the new end. Cliff
+    /// lockups can only be extended before they end, and a `Daily` lockup
+    /// can only become a `Cliff` one before its first day vests.
+    pub fn extend_lockup(&mut self, days: u64, kind: LockupKind, curr_ts: i64) -> Result<()> {
+        if let (LockupKind::Cliff, LockupKind::Daily) = (self.lockup.kind, kind) {
+            return Err(ErrorCode::InvalidLockupKind.into());
+        }
+        require!(days > 0, InvalidDays);
+        let secs = i64::try_from(days)
+            .ok()
+            .and_then(|days| days.checked_mul(SECS_PER_DAY))
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+
+        let mut extended = *self;
+        extended.lockup.kind = kind;
+        extended.lockup.end_ts = self
+            .lockup
+            .end_ts
+            .checked_add(secs)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        if let LockupKind::Daily = kind {
+            extended.lockup.start_ts = self
+                .lockup
+                .start_ts
+                .checked_sub(self.extension_start_shift(secs, curr_ts)?)
+                .ok_or(ErrorCode::ArithmeticOverflow)?;
+        }
+        require!(
+            extended.vested_at(curr_ts)? >= self.vested_at(curr_ts)?,
+            VestedAmountDecrease
+        );
+
+        *self = extended;
+        Ok(())
+    }
+
+    /// Returns how many seconds to move the start of a `Daily` lockup back
+    /// when its end moves `secs` further out, so that the vested share of the
+    /// deposit at `curr_ts` doesn't shrink.
+    ///
+    /// With `c` of `t` units of the lockup's granularity elapsed and `a` units
+    /// added, moving the start back by `k` units vests `(c + k) / (t + a + k)`
+    /// of the deposit, which is at least `c / t` for `k >= c * a / (t - c)`.
+    /// Fully vested lockups can't keep their vested share, and return zero.
+    fn extension_start_shift(&self, secs: i64, curr_ts: i64) -> Result<i64> {
+        let unit = self.lockup.granularity.unit_secs();
+        let elapsed = curr_ts.saturating_sub(self.lockup.start_ts).max(0) / unit;
+        let total = self.lockup.end_ts.saturating_sub(self.lockup.start_ts) / unit;
+        if elapsed == 0 || elapsed >= total {
+            return Ok(0);
+        }
+        let (elapsed, left, added) = (
+            elapsed as u128,
+            (total - elapsed) as u128,
+            (secs / unit) as u128,
+        );
+        // Rounded up, so that the vested share never shrinks.
+        let shift = (elapsed * added + left - 1) / left * unit as u128;
+        i64::try_from(shift).map_err(|_| ErrorCode::ArithmeticOverflow.into())
+    }
+
+    /// Restarts the lockup at `curr_ts`, to last for `days`.
+    ///
+    /// Like `extend_lockup`, this is rejected if it would lower the amount
+    /// vested at `curr_ts`. Since the restarted lockup hasn't vested anything
+    /// yet, only lockups without vested tokens can be reset.
+    pub fn reset_lockup(&mut self, days: u64, curr_ts: i64) -> Result<()> {
+        let secs = i64::try_from(days)
+            .ok()
+            .and_then(|days| days.checked_mul(SECS_PER_DAY))
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+
+        let mut reset = *self;
+        reset.lockup.start_ts = curr_ts;
+        reset.lockup.end_ts = curr_ts
+            .checked_add(secs)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        require!(
+            reset.vested_at(curr_ts)? >= self.vested_at(curr_ts)?,
+            VestedAmountDecrease
+        );
+
+        *self = reset;
+        Ok(())
+    }
+
     /// Returns the amount left in the deposit, ignoring the vesting schedule.
-    pub fn amount_left(&self) -> u64 {
+    pub fn amount_left(&self) -> Result<u64> {
         self.amount_deposited
             .checked_sub(self.amount_withdrawn)
-            .unwrap()
+            .ok_or_else(|| ErrorCode::ArithmeticOverflow.into())
     }
 }
 
@@ -288,23 +1210,36 @@ pub struct Lockup {
     pub start_ts: i64,
     // End of the lockup.
     pub end_ts: i64,
+    // Whether vesting and voting power move in whole days or continuously.
+    // Added in voter version 2.
+    pub granularity: LockupGranularity,
     // Empty bytes for future upgrades.
-    pub padding: [u8; 16],
+    pub padding: [u8; 15],
 }
 
 impl Lockup {
-    /// Returns the number of days left on the lockup.
+    /// Returns the number of days left on the lockup. Partial days are
+    /// rounded down for `LockupGranularity::Seconds` lockups.
     pub fn days_left(&self, curr_ts: i64) -> Result<u64> {
-        Ok(self
-            .days_total()?
-            .saturating_sub(self.day_current(curr_ts)?))
+        match self.granularity {
+            LockupGranularity::Days => Ok(self
+                .days_total()?
+                .saturating_sub(self.day_current(curr_ts)?)),
+            LockupGranularity::Seconds => Ok(self.secs_left(curr_ts) / SECS_PER_DAY as u64),
+        }
+    }
+
+    /// Returns the number of seconds left on the lockup.
+    pub fn secs_left(&self, curr_ts: i64) -> u64 {
+        let secs = self.end_ts.saturating_sub(curr_ts.max(self.start_ts));
+        u64::try_from(secs).unwrap_or(0)
     }
 
     /// Returns the current day in the vesting schedule.
     pub fn day_current(&self, curr_ts: i64) -> Result<u64> {
         let d = u64::try_from({
-            let secs_elapsed = curr_ts.saturating_sub(self.start_ts);
-            secs_elapsed.checked_div(SECS_PER_DAY).unwrap()
+            let secs_elapsed = curr_ts.saturating_sub(self.start_ts).max(0);
+            secs_elapsed / SECS_PER_DAY
         })
         .map_err(|_| ErrorCode::UnableToConvert)?;
         Ok(d)
@@ -313,11 +1248,17 @@ impl Lockup {
     /// Returns the total amount of days in the lockup period.
     pub fn days_total(&self) -> Result<u64> {
         // Number of seconds in the entire lockup.
-        let lockup_secs = self.end_ts.checked_sub(self.start_ts).unwrap();
-        require!(lockup_secs % SECS_PER_DAY == 0, InvalidLockupPeriod);
+        let lockup_secs = self
+            .end_ts
+            .checked_sub(self.start_ts)
+            .ok_or(ErrorCode::InvalidEndTs)?;
+        if self.granularity == LockupGranularity::Days {
+            require!(lockup_secs % SECS_PER_DAY == 0, InvalidLockupPeriod);
+        }
 
-        // Total days in the entire lockup.
-        let lockup_days = u64::try_from(lockup_secs.checked_div(SECS_PER_DAY).unwrap()).unwrap();
+        // Total days in the entire lockup, rounded down.
+        let lockup_days =
+            u64::try_from(lockup_secs / SECS_PER_DAY).map_err(|_| ErrorCode::InvalidEndTs)?;
 
         Ok(lockup_days)
     }
@@ -330,9 +1271,103 @@ pub enum LockupKind {
     Cliff,
 }
 
+/// Time resolution of a lockup.
+#[repr(u8)]
+#[derive(AnchorSerialize, AnchorDeserialize, Debug, Clone, Copy, PartialEq)]
+pub enum LockupGranularity {
+    // Lockups last whole days. `Daily` lockups vest once a day and voting
+    // power decays in daily steps.
+    Days,
+    // Lockups can end at any timestamp. `Daily` lockups vest continuously and
+    // voting power decays every second.
+    Seconds,
+}
+
+impl LockupGranularity {
+    /// Returns the number of seconds in one unit of lockup duration.
+    pub fn unit_secs(&self) -> i64 {
+        match self {
+            LockupGranularity::Days => SECS_PER_DAY,
+            LockupGranularity::Seconds => 1,
+        }
+    }
+}
+
+#[repr(u8)]
+#[derive(AnchorSerialize, AnchorDeserialize, Debug, Clone, Copy, PartialEq)]
+pub enum VotingTokenMode {
+    // One voting mint per exchange rate, in native units of the deposit.
+    PerMint,
+    // No voting tokens are issued.
+    Disabled,
+    // A single voting mint for the registrar, in the common currency.
+    Scaled,
+}
+
+impl VotingTokenMode {
+    /// Returns the amount of voting tokens issued for the deposit.
+    pub fn voting_tokens(&self, d: &DepositEntry) -> u64 {
+        match self {
+            VotingTokenMode::PerMint => d.amount_deposited,
+            VotingTokenMode::Disabled => 0,
+            VotingTokenMode::Scaled => d.amount_scaled,
+        }
+    }
+}
+
+#[repr(u8)]
+#[derive(AnchorSerialize, AnchorDeserialize, Debug, Clone, Copy, PartialEq)]
+pub enum MaxVoteWeightMode {
+    // The total supply of all mints, converted into the common currency.
+    // Rates can't be boosted above 100% in this mode.
+    Supply,
+    // The voting power of the total supply of all mints, as if it were all
+    // locked up for the max lockup period.
+    LockedSupply,
+    // The voting power of all deposits tracked by the registrar, as if they
+    // were all locked up for the max lockup period.
+    DepositTotals,
+}
+
+/// How a voter's deposit voting powers are combined into its voter weight.
+#[repr(u8)]
+#[derive(AnchorSerialize, AnchorDeserialize, Debug, Clone, Copy, PartialEq)]
+pub enum WeightAggregation {
+    // The sum of the voting powers.
+    Linear,
+    // The square root of the sum, in whole units of the common currency, so
+    // that four tokens of voting power weigh as much as two.
+    Quadratic,
+}
+
+/// The SPL governance action a voter weight is measured for. Mirrors
+/// `VoterWeightAction` so that it can be used as an instruction argument.
+#[repr(u8)]
+#[derive(AnchorSerialize, AnchorDeserialize, Debug, Clone, Copy, PartialEq)]
+pub enum WeightAction {
+    CastVote,
+    CommentProposal,
+    CreateGovernance,
+    CreateProposal,
+    SignOffProposal,
+}
+
+impl From<WeightAction> for VoterWeightAction {
+    fn from(action: WeightAction) -> Self {
+        match action {
+            WeightAction::CastVote => VoterWeightAction::CastVote,
+            WeightAction::CommentProposal => VoterWeightAction::CommentProposal,
+            WeightAction::CreateGovernance => VoterWeightAction::CreateGovernance,
+            WeightAction::CreateProposal => VoterWeightAction::CreateProposal,
+            WeightAction::SignOffProposal => VoterWeightAction::SignOffProposal,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::oracle::PRICE_FEED_MAGIC;
 
     #[test]
     pub fn days_left_start() -> Result<()> {
@@ -416,11 +1451,13 @@ mod tests {
     }
 
     #[test]
-    pub fn voting_power_cliff_warmup() -> Result<()> {
+    pub fn voting_power_cliff_before_start() -> Result<()> {
         // 10 tokens with 6 decimals.
         let amount_deposited = 10 * 1_000_000;
+        // Locked tokens count fully before an aligned lockup starts.
+        let expected_voting_power = (10 * amount_deposited) / MAX_DAYS_LOCKED;
         run_test_voting_power(TestVotingPower {
-            expected_voting_power: 0, // 0 warmup.
+            expected_voting_power,
             amount_deposited,
             days_total: 10.0,
             curr_day: -0.5,
@@ -575,10 +1612,14 @@ mod tests {
     }
 
     #[test]
-    pub fn voting_power_daily_warmup() -> Result<()> {
+    pub fn voting_power_daily_before_start() -> Result<()> {
+        // 10 tokens with 6 decimals.
+        let amount_deposited = 10 * 1_000_000;
+        // Locked tokens count fully before an aligned lockup starts.
+        let expected_voting_power = locked_daily_power(amount_deposited, 10);
         run_test_voting_power(TestVotingPower {
-            expected_voting_power: 0, // (0/MAX_DAYS_LOCKED) * deposit w/ 6 decimals.
-            amount_deposited: 10 * 1_000_000, // 10 tokens with 6 decimals.
+            expected_voting_power,
+            amount_deposited,
             days_total: 10.0,
             curr_day: -1.0,
             kind: LockupKind::Daily,
@@ -725,13 +1766,1071 @@ mod tests {
         })
     }
 
-    struct TestDaysLeft {
-        expected_days_left: u64,
-        days_total: f64,
-        curr_day: f64,
-    }
-
-    struct TestVotingPower {
+    #[test]
+    pub fn voting_power_cliff_boosted_start() -> Result<()> {
+        // 10 tokens with 6 decimals.
+        let amount_deposited = 10 * 1_000_000;
+        // 10 of 100 max lockup days with a 3x boost.
+        let expected_voting_power = (3 * 10 * amount_deposited) / 100;
+        run_test_voting_power_with_rate(
+            TestVotingPower {
+                expected_voting_power,
+                amount_deposited,
+                days_total: 10.0,
+                curr_day: 0.5,
+                kind: LockupKind::Cliff,
+            },
+            exchange_rate_entry(100, 300),
+        )
+    }
+
+    #[test]
+    pub fn voting_power_cliff_boosted_max() -> Result<()> {
+        // 10 tokens with 6 decimals.
+        let amount_deposited = 10 * 1_000_000;
+        run_test_voting_power_with_rate(
+            TestVotingPower {
+                expected_voting_power: 3 * amount_deposited,
+                amount_deposited,
+                days_total: 100.0,
+                curr_day: 0.0,
+                kind: LockupKind::Cliff,
+            },
+            exchange_rate_entry(100, 300),
+        )
+    }
+
+    #[test]
+    pub fn voting_power_daily_boosted_two() -> Result<()> {
+        // 10 tokens with 6 decimals.
+        let amount_deposited = 10 * 1_000_000;
+        // 1.5x boost over a 365 day max lockup.
+        let expected_voting_power = locked_daily_power_with(amount_deposited, 8, 365, 150);
+        run_test_voting_power_with_rate(
+            TestVotingPower {
+                expected_voting_power,
+                amount_deposited,
+                days_total: 10.0,
+                curr_day: 2.0,
+                kind: LockupKind::Daily,
+            },
+            exchange_rate_entry(365, 150),
+        )
+    }
+
+    #[test]
+    pub fn price_feed_load() -> Result<()> {
+        let feed = PriceFeed {
+            magic: PRICE_FEED_MAGIC,
+            rate: 42,
+            publish_slot: 100,
+        };
+        let data = feed.try_to_vec().unwrap();
+        let loaded = PriceFeed::load(&data)?;
+        assert_eq!(loaded.rate, 42);
+        assert_eq!(loaded.publish_slot, 100);
+        Ok(())
+    }
+
+    #[test]
+    pub fn price_feed_load_invalid() {
+        let feed = PriceFeed {
+            magic: 0,
+            rate: 42,
+            publish_slot: 100,
+        };
+        let data = feed.try_to_vec().unwrap();
+        assert!(PriceFeed::load(&data).is_err());
+        assert!(PriceFeed::load(&data[..4]).is_err());
+    }
+
+    #[test]
+    pub fn oracle_rate_update() -> Result<()> {
+        let mut er = oracle_exchange_rate_entry();
+        er.update_from_feed(&price_feed(15, 95), 100)?;
+        assert_eq!({ er.rate }, 15);
+        assert_eq!({ er.last_update_slot }, 95);
+        er.require_fresh(105)?;
+        assert!(er.require_fresh(106).is_err());
+        Ok(())
+    }
+
+    #[test]
+    pub fn oracle_rate_update_stale() {
+        let mut er = oracle_exchange_rate_entry();
+        assert!(er.update_from_feed(&price_feed(15, 89), 100).is_err());
+        assert_eq!({ er.rate }, 10);
+    }
+
+    #[test]
+    pub fn oracle_rate_update_out_of_bounds() {
+        let mut er = oracle_exchange_rate_entry();
+        assert!(er.update_from_feed(&price_feed(4, 100), 100).is_err());
+        assert!(er.update_from_feed(&price_feed(21, 100), 100).is_err());
+        assert_eq!({ er.rate }, 10);
+    }
+
+    #[test]
+    pub fn oracle_rate_never_refreshed() {
+        let er = oracle_exchange_rate_entry();
+        assert!(er.require_fresh(1).is_err());
+    }
+
+    #[test]
+    pub fn convert_integer_rate() -> Result<()> {
+        run_test_convert(TestConvert {
+            expected_converted: 2_000_000_000,
+            amount: 1_000,
+            rate: 2,
+            rate_denominator: 1,
+            decimals: 0,
+            rate_decimals: 6,
+        })
+    }
+
+    #[test]
+    pub fn convert_fractional_rate() -> Result<()> {
+        run_test_convert(TestConvert {
+            expected_converted: 250_000,
+            amount: 1_000_000,
+            rate: 1,
+            rate_denominator: 4,
+            decimals: 6,
+            rate_decimals: 6,
+        })
+    }
+
+    #[test]
+    pub fn convert_fractional_rate_rounds_down() -> Result<()> {
+        run_test_convert(TestConvert {
+            expected_converted: 3,
+            amount: 10,
+            rate: 1,
+            rate_denominator: 3,
+            decimals: 6,
+            rate_decimals: 6,
+        })
+    }
+
+    #[test]
+    pub fn convert_more_mint_decimals() -> Result<()> {
+        // 1.5 tokens with 9 decimals under a 6 decimal registrar.
+        run_test_convert(TestConvert {
+            expected_converted: 1_500_000,
+            amount: 1_500_000_000,
+            rate: 1,
+            rate_denominator: 1,
+            decimals: 9,
+            rate_decimals: 6,
+        })
+    }
+
+    #[test]
+    pub fn convert_more_mint_decimals_fractional_rate() -> Result<()> {
+        // 1.5 tokens with 9 decimals at 0.25 under a 6 decimal registrar.
+        run_test_convert(TestConvert {
+            expected_converted: 375_000,
+            amount: 1_500_000_000,
+            rate: 1,
+            rate_denominator: 4,
+            decimals: 9,
+            rate_decimals: 6,
+        })
+    }
+
+    #[test]
+    pub fn convert_overflow() {
+        let r = run_test_convert(TestConvert {
+            expected_converted: 0,
+            amount: u64::MAX,
+            rate: 2,
+            rate_denominator: 1,
+            decimals: 6,
+            rate_decimals: 6,
+        });
+        assert!(r.is_err());
+    }
+
+    #[test]
+    pub fn convert_intermediate_overflow() {
+        let r = run_test_convert(TestConvert {
+            expected_converted: 0,
+            amount: u64::MAX,
+            rate: u64::MAX,
+            rate_denominator: u64::MAX,
+            decimals: 0,
+            rate_decimals: 12,
+        });
+        assert_error(r, ErrorCode::UnableToConvert);
+    }
+
+    #[test]
+    pub fn migrate_registrar() -> Result<()> {
+        let mut registrar = Registrar::zeroed();
+        registrar.migrate()?;
+        assert_eq!(registrar.version, REGISTRAR_VERSION);
+        assert!(registrar.migrate().is_err());
+        Ok(())
+    }
+
+    #[test]
+    pub fn migrate_registrar_clears_pause_flags() -> Result<()> {
+        let mut registrar = Registrar::zeroed();
+        registrar.version = 3;
+        registrar.pause_flags.withdrawals = true;
+        registrar.migrate()?;
+        assert!(!registrar.pause_flags.withdrawals);
+        Ok(())
+    }
+
+    #[test]
+    pub fn migrate_registrar_keeps_boosted_max_vote_weight() -> Result<()> {
+        let mut registrar = Registrar::zeroed();
+        registrar.version = 2;
+        registrar.rates[1] = exchange_rate_entry(365, 250);
+        registrar.migrate()?;
+        assert_eq!(
+            { registrar.max_vote_weight_mode },
+            MaxVoteWeightMode::LockedSupply
+        );
+        Ok(())
+    }
+
+    // `Registrar` and `ExchangeRateEntry` as they were before accounts were
+    // versioned. Only written as bytes.
+    #[zero_copy]
+    #[allow(dead_code)]
+    struct UnversionedRegistrar {
+        authority: Pubkey,
+        realm: Pubkey,
+        realm_community_mint: Pubkey,
+        bump: u8,
+        rates: [UnversionedExchangeRateEntry; 2],
+        rate_decimals: u8,
+    }
+
+    #[zero_copy]
+    #[allow(dead_code)]
+    struct UnversionedExchangeRateEntry {
+        mint: Pubkey,
+        rate: u64,
+        decimals: u8,
+    }
+
+    unsafe impl Pod for UnversionedRegistrar {}
+    unsafe impl Zeroable for UnversionedRegistrar {}
+
+    #[test]
+    pub fn migrate_unversioned_registrar() -> Result<()> {
+        let rate_mint = Pubkey::new_unique();
+        let unversioned = UnversionedRegistrar {
+            authority: Pubkey::new_unique(),
+            realm: Pubkey::new_unique(),
+            realm_community_mint: Pubkey::new_unique(),
+            bump: 254,
+            rates: [
+                UnversionedExchangeRateEntry {
+                    mint: rate_mint,
+                    rate: 3,
+                    decimals: 6,
+                },
+                UnversionedExchangeRateEntry {
+                    mint: Pubkey::default(),
+                    rate: 0,
+                    decimals: 0,
+                },
+            ],
+            rate_decimals: 9,
+        };
+        let mut data = Registrar::discriminator().to_vec();
+        data.extend_from_slice(bytemuck::bytes_of(&unversioned));
+        assert_eq!(data.len(), 8 + UNVERSIONED_REGISTRAR_LEN);
+
+        let registrar = Registrar::from_unversioned(&data)?;
+        assert_eq!(registrar.version, REGISTRAR_VERSION);
+        assert_eq!(registrar.authority, unversioned.authority);
+        assert_eq!(registrar.realm, unversioned.realm);
+        assert_eq!(
+            registrar.realm_governing_token_mint,
+            unversioned.realm_community_mint
+        );
+        assert_eq!(registrar.bump, 254);
+        let er = registrar.rates[0];
+        assert_eq!(er.mint, rate_mint);
+        assert_eq!({ er.rate }, 3);
+        assert_eq!({ er.rate_denominator }, 1);
+        assert_eq!(er.decimals, 6);
+        assert_eq!({ er.max_lockup_days }, MAX_DAYS_LOCKED);
+        assert_eq!({ er.max_boost_pct }, BOOST_PCT_DENOMINATOR);
+        assert!(!er.is_oracle());
+        assert_eq!({ registrar.rates[1].rate }, 0);
+        assert_eq!({ registrar.rates[1].rate_denominator }, 0);
+        assert_eq!(registrar.rate_decimals, 9);
+        assert_eq!({ registrar.weight_policies[0].min_lockup_days }, 0);
+        assert_eq!(registrar.weight_policies[0].excluded_rates, 0);
+        assert_eq!({ registrar.voting_token_mode }, VotingTokenMode::PerMint);
+        assert_eq!(
+            { registrar.max_vote_weight_mode },
+            MaxVoteWeightMode::Supply
+        );
+        // Deposits convert as before.
+        assert_eq!(registrar.convert(&er, 10)?, 30_000);
+        Ok(())
+    }
+
+    #[test]
+    pub fn migrate_unversioned_registrar_wrong_size() {
+        let mut data = Registrar::discriminator().to_vec();
+        data.extend_from_slice(&[0u8; UNVERSIONED_REGISTRAR_LEN + 1]);
+        assert_error(
+            Registrar::from_unversioned(&data).map(|_| ()),
+            ErrorCode::InvalidUnversionedAccount,
+        );
+    }
+
+    #[test]
+    pub fn migrate_unversioned_voter() -> Result<()> {
+        let (authority, registrar) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let mut data = Voter::discriminator().to_vec();
+        data.extend_from_slice(authority.as_ref());
+        data.extend_from_slice(registrar.as_ref());
+        // voter_bump, voter_weight_record_bump
+        data.extend_from_slice(&[253, 252]);
+        // deposits[0]: is_used, rate_idx, amount_deposited, amount_withdrawn,
+        // amount_scaled, lockup kind, start_ts, end_ts, padding
+        data.extend_from_slice(&[1, 1]);
+        data.extend_from_slice(&100u64.to_le_bytes());
+        data.extend_from_slice(&40u64.to_le_bytes());
+        data.extend_from_slice(&60u64.to_le_bytes());
+        data.push(LockupKind::Cliff as u8);
+        data.extend_from_slice(&1634929833i64.to_le_bytes());
+        data.extend_from_slice(&1635793833i64.to_le_bytes());
+        data.extend_from_slice(&[0u8; 16]);
+        // deposits[1..]
+        data.extend_from_slice(&[0u8; 31 * 59]);
+        assert_eq!(data.len(), 8 + UNVERSIONED_VOTER_LEN);
+
+        let voter = Voter::from_unversioned(&data)?;
+        assert_eq!(voter.version, VOTER_VERSION);
+        assert_eq!(voter.authority, authority);
+        assert_eq!(voter.registrar, registrar);
+        assert_eq!(voter.voter_bump, 253);
+        assert_eq!(voter.voter_weight_record_bump, 252);
+        let d = voter.deposits[0];
+        assert!(d.is_used);
+        assert_eq!(d.rate_idx, 1);
+        assert_eq!({ d.amount_deposited }, 100);
+        assert_eq!({ d.amount_withdrawn }, 40);
+        assert_eq!({ d.amount_scaled }, 60);
+        assert!(matches!(d.lockup.kind, LockupKind::Cliff));
+        assert_eq!({ d.lockup.start_ts }, 1634929833);
+        assert_eq!({ d.lockup.end_ts }, 1635793833);
+        assert_eq!({ d.lockup.granularity }, LockupGranularity::Days);
+        assert!(!voter.deposits[1].is_used);
+        Ok(())
+    }
+
+    #[test]
+    pub fn migrate_voter() -> Result<()> {
+        let mut voter = Voter::zeroed();
+        voter.deposits[0].lockup.granularity = LockupGranularity::Seconds;
+        voter.migrate()?;
+        assert_eq!(voter.version, VOTER_VERSION);
+        assert_eq!(
+            { voter.deposits[0].lockup.granularity },
+            LockupGranularity::Days
+        );
+        assert_eq!(voter.capacity, VOTER_MAX_CAPACITY);
+        assert!(voter.migrate().is_err());
+        Ok(())
+    }
+
+    #[test]
+    pub fn voter_weight() -> Result<()> {
+        let (registrar, _, _) = registrar_with_two_rates();
+        let mut voter = Voter::zeroed();
+        voter.deposits[0] = locked_deposit(LockupKind::Cliff, MAX_DAYS_LOCKED);
+        voter.deposits[1] = locked_deposit(LockupKind::Cliff, MAX_DAYS_LOCKED);
+        let curr_ts = voter.deposits[0].lockup.start_ts;
+        let weight = voter.weight_at(&registrar, WeightAction::CastVote, curr_ts, 0)?;
+        assert_eq!(weight, 20_000);
+        Ok(())
+    }
+
+    #[test]
+    pub fn voter_weight_overflow() {
+        let (registrar, _, _) = registrar_with_two_rates();
+        let mut voter = Voter::zeroed();
+        for d in voter.deposits[..2].iter_mut() {
+            *d = DepositEntry {
+                amount_deposited: u64::MAX,
+                amount_scaled: u64::MAX,
+                ..locked_deposit(LockupKind::Cliff, MAX_DAYS_LOCKED)
+            };
+        }
+        let curr_ts = voter.deposits[0].lockup.start_ts;
+        assert_error(
+            voter
+                .weight_at(&registrar, WeightAction::CastVote, curr_ts, 0)
+                .map(|_| ()),
+            ErrorCode::ArithmeticOverflow,
+        );
+    }
+
+    #[test]
+    pub fn voter_capped_weight() -> Result<()> {
+        let (mut registrar, _, _) = registrar_with_two_rates();
+        registrar.voter_weight_cap.max_weight = 15_000;
+        let mut voter = Voter::zeroed();
+        voter.deposits[0] = locked_deposit(LockupKind::Cliff, MAX_DAYS_LOCKED);
+        voter.deposits[1] = locked_deposit(LockupKind::Cliff, MAX_DAYS_LOCKED);
+        let curr_ts = voter.deposits[0].lockup.start_ts;
+        let weight = voter.capped_weight_at(&registrar, WeightAction::CastVote, curr_ts, 0)?;
+        assert_eq!(weight, 15_000);
+        assert_eq!({ voter.last_uncapped_weight }, 20_000);
+        Ok(())
+    }
+
+    #[test]
+    pub fn migrate_voter_version_2() -> Result<()> {
+        let authority = Pubkey::new_unique();
+        let mut data = Voter::discriminator().to_vec();
+        // version, authority, registrar, voter_bump,
+        // voter_weight_record_bump
+        data.push(2);
+        data.extend_from_slice(authority.as_ref());
+        data.extend_from_slice(&[0u8; 32]);
+        data.extend_from_slice(&[253, 252]);
+        // deposits[0]: is_used, rate_idx, amount_deposited
+        data.extend_from_slice(&[1, 1]);
+        data.extend_from_slice(&100u64.to_le_bytes());
+        data.extend_from_slice(&[0u8; 59 - 10]);
+        // deposits[1..], reserved
+        data.extend_from_slice(&[0u8; 31 * 59 + 64]);
+        assert_eq!(data.len(), 8 + size_of::<Voter>());
+
+        let mut voter = Voter::from_any_version(&data)?;
+        assert_eq!(voter.version, 2);
+        voter.migrate()?;
+        let mut migrated = vec![0u8; data.len()];
+        voter.try_serialize(&mut &mut migrated[..])?;
+
+        let voter = Voter::try_deserialize(&mut &migrated[..])?;
+        assert_eq!(voter.version, VOTER_VERSION);
+        assert_eq!(voter.capacity, VOTER_MAX_CAPACITY);
+        assert_eq!(voter.authority, authority);
+        assert_eq!(voter.voter_bump, 253);
+        assert_eq!(voter.voter_weight_record_bump, 252);
+        assert!(voter.deposits[0].is_used);
+        assert_eq!(voter.deposits[0].rate_idx, 1);
+        assert_eq!({ voter.deposits[0].amount_deposited }, 100);
+        assert!(!voter.deposits[1].is_used);
+        Ok(())
+    }
+
+    #[test]
+    pub fn voter_not_migrated() {
+        let mut data = Voter::discriminator().to_vec();
+        data.push(2);
+        data.extend_from_slice(&[0u8; size_of::<Voter>() - 1]);
+        assert_error(
+            Voter::try_deserialize(&mut &data[..]).map(|_| ()),
+            ErrorCode::VoterNotMigrated,
+        );
+    }
+
+    #[test]
+    pub fn voter_discriminator() {
+        let hash = anchor_lang::solana_program::hash::hash(b"account:Voter");
+        assert_eq!(Voter::discriminator(), hash.to_bytes()[..8]);
+    }
+
+    #[test]
+    pub fn voter_space() {
+        assert_eq!(Voter::space(0), 8 + 131);
+        assert_eq!(Voter::space(VOTER_INITIAL_CAPACITY), 8 + 131 + 4 * 59);
+        assert_eq!(Voter::space(VOTER_MAX_CAPACITY), 8 + size_of::<Voter>());
+    }
+
+    #[test]
+    pub fn voter_round_trip() -> Result<()> {
+        // New accounts are all zeros and take their capacity from their size.
+        let data = vec![0u8; Voter::space(VOTER_INITIAL_CAPACITY)];
+        let mut voter = Voter::try_deserialize_unchecked(&mut &data[..])?;
+        assert_eq!(voter.capacity, VOTER_INITIAL_CAPACITY);
+        voter.version = VOTER_VERSION;
+        voter.voter_bump = 255;
+        for d in voter.deposits[..3].iter_mut() {
+            d.is_used = true;
+        }
+        assert_eq!(voter.free_deposit_idx(), Some(3));
+        voter.deposits[3].is_used = true;
+        assert_eq!(voter.free_deposit_idx(), None);
+
+        let mut data = vec![0u8; Voter::space(VOTER_INITIAL_CAPACITY)];
+        voter.try_serialize(&mut &mut data[..])?;
+        let voter = Voter::try_deserialize(&mut &data[..])?;
+        assert_eq!(voter.capacity, VOTER_INITIAL_CAPACITY);
+        assert_eq!(voter.voter_bump, 255);
+        assert!(voter.deposits[3].is_used);
+        assert!(!voter.deposits[4].is_used);
+        // The account has no room for entries past its capacity.
+        assert!(voter
+            .try_serialize(&mut &mut data[..Voter::space(3)])
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    pub fn voter_deposit_ids() -> Result<()> {
+        let mut voter = Voter::zeroed();
+        voter.capacity = VOTER_INITIAL_CAPACITY;
+        voter.deposits[1].is_used = true;
+        assert!(voter.used_deposit_mut(1)?.is_used);
+        assert!(!voter.deposit_mut(3)?.is_used);
+        assert_error(
+            voter.used_deposit_mut(0).map(|_| ()),
+            ErrorCode::InvalidDepositId,
+        );
+        // Entries past the capacity aren't stored, even if marked used.
+        voter.deposits[VOTER_INITIAL_CAPACITY as usize].is_used = true;
+        assert_error(
+            voter.used_deposit_mut(VOTER_INITIAL_CAPACITY).map(|_| ()),
+            ErrorCode::InvalidDepositId,
+        );
+        Ok(())
+    }
+
+    #[test]
+    pub fn require_rate_mints() -> Result<()> {
+        let (registrar, a, b) = registrar_with_two_rates();
+        registrar.require_rate_mints(&[a, b])
+    }
+
+    #[test]
+    pub fn require_rate_mints_missing() {
+        let (registrar, a, _) = registrar_with_two_rates();
+        assert_error(
+            registrar.require_rate_mints(&[a]),
+            ErrorCode::MissingMintAccount,
+        );
+    }
+
+    #[test]
+    pub fn require_rate_mints_duplicate() {
+        let (registrar, a, _) = registrar_with_two_rates();
+        assert_error(
+            registrar.require_rate_mints(&[a, a]),
+            ErrorCode::DuplicateMintAccount,
+        );
+    }
+
+    #[test]
+    pub fn require_rate_mints_out_of_order() {
+        let (registrar, a, b) = registrar_with_two_rates();
+        assert_error(
+            registrar.require_rate_mints(&[b, a]),
+            ErrorCode::MintAccountOutOfOrder,
+        );
+    }
+
+    #[test]
+    pub fn require_rate_mints_unknown() {
+        let (registrar, a, b) = registrar_with_two_rates();
+        assert_error(
+            registrar.require_rate_mints(&[a, b, Pubkey::new_unique()]),
+            ErrorCode::ExchangeRateEntryNotFound,
+        );
+    }
+
+    #[test]
+    pub fn require_rate_mints_skips_empty_rates() -> Result<()> {
+        let (mut registrar, a, _) = registrar_with_two_rates();
+        registrar.rates[1] = ExchangeRateEntry::zeroed();
+        registrar.require_rate_mints(&[a])
+    }
+
+    #[test]
+    pub fn max_voting_power_supply() -> Result<()> {
+        run_test_max_voting_power(MaxVoteWeightMode::Supply, 1_000)
+    }
+
+    #[test]
+    pub fn max_voting_power_locked_supply() -> Result<()> {
+        run_test_max_voting_power(MaxVoteWeightMode::LockedSupply, 2_500)
+    }
+
+    #[test]
+    pub fn max_voting_power_deposit_totals() -> Result<()> {
+        run_test_max_voting_power(MaxVoteWeightMode::DepositTotals, 2_500)
+    }
+
+    #[test]
+    pub fn deposit_rate_idx() -> Result<()> {
+        let (registrar, _, b) = registrar_with_two_rates();
+        let mut d = locked_deposit(LockupKind::Cliff, 10);
+        d.rate_idx = 1;
+        assert_eq!(registrar.deposit_rate_idx(&d, &b)?, 1);
+        Ok(())
+    }
+
+    #[test]
+    pub fn deposit_rate_idx_other_mint() {
+        let (registrar, a, _) = registrar_with_two_rates();
+        let mut d = locked_deposit(LockupKind::Cliff, 10);
+        d.rate_idx = 1;
+        assert_error(
+            registrar.deposit_rate_idx(&d, &a).map(|_| ()),
+            ErrorCode::InvalidDepositMint,
+        );
+    }
+
+    #[test]
+    pub fn deposit_rate_idx_unused() {
+        let (registrar, a, _) = registrar_with_two_rates();
+        let mut d = locked_deposit(LockupKind::Cliff, 10);
+        d.is_used = false;
+        assert_error(
+            registrar.deposit_rate_idx(&d, &a).map(|_| ()),
+            ErrorCode::InvalidDepositId,
+        );
+    }
+
+    #[test]
+    pub fn is_valid_boost() {
+        let mut registrar = Registrar::zeroed();
+        registrar.max_vote_weight_mode = MaxVoteWeightMode::Supply;
+        assert!(registrar.is_valid_boost(&exchange_rate_entry(365, 100)));
+        assert!(!registrar.is_valid_boost(&exchange_rate_entry(365, 101)));
+        registrar.max_vote_weight_mode = MaxVoteWeightMode::LockedSupply;
+        assert!(registrar.is_valid_boost(&exchange_rate_entry(365, 250)));
+        registrar.max_vote_weight_mode = MaxVoteWeightMode::DepositTotals;
+        assert!(registrar.is_valid_boost(&exchange_rate_entry(365, 250)));
+    }
+
+    #[test]
+    pub fn deposit_totals() -> Result<()> {
+        let mut totals = DepositTotals::default();
+        totals.record_deposit(100, 0, 400)?;
+        totals.record_deposit(50, 0, 200)?;
+        totals.record_withdrawal(30, 400, 280)?;
+        assert_eq!({ totals.deposited }, 150);
+        assert_eq!({ totals.withdrawn }, 30);
+        assert_eq!({ totals.amount_scaled }, 480);
+        assert_eq!(totals.amount_held(), 120);
+        Ok(())
+    }
+
+    #[test]
+    pub fn deposit_totals_untracked_withdrawal() -> Result<()> {
+        let mut totals = DepositTotals::default();
+        totals.record_withdrawal(30, 120, 0)?;
+        assert_eq!(totals.amount_held(), 0);
+        assert_eq!({ totals.amount_scaled }, 0);
+        Ok(())
+    }
+
+    #[test]
+    pub fn deposit_totals_overflow() {
+        let mut totals = DepositTotals::default();
+        totals.deposited = u64::MAX;
+        assert_error(
+            totals.record_deposit(1, 0, 1),
+            ErrorCode::ArithmeticOverflow,
+        );
+    }
+
+    #[test]
+    pub fn days_total_end_before_start() {
+        let lockup = Lockup {
+            kind: LockupKind::Daily,
+            start_ts: SECS_PER_DAY,
+            end_ts: 0,
+            granularity: LockupGranularity::Days,
+            padding: [0u8; 15],
+        };
+        assert_error(lockup.days_total().map(|_| ()), ErrorCode::InvalidEndTs);
+    }
+
+    #[test]
+    pub fn voting_power_cliff_secs_one_third_day() -> Result<()> {
+        let mut d = locked_deposit(LockupKind::Cliff, 10);
+        d.lockup.granularity = LockupGranularity::Seconds;
+        d.amount_scaled = 10 * 1_000_000;
+        let curr_ts = d.lockup.start_ts + days_to_secs(0.33);
+        let er = exchange_rate_entry(MAX_DAYS_LOCKED, 100);
+        // 10 * 1_000_000 * (864_000 - 28_512) / (2555 * 86_400), where whole
+        // days would give 10 * 1_000_000 * 10 / 2555 = 39_138.
+        assert_eq!(d.voting_power(&er, curr_ts)?, 37_847);
+        Ok(())
+    }
+
+    #[test]
+    pub fn voting_power_daily_secs_one_third_day() -> Result<()> {
+        let mut d = locked_deposit(LockupKind::Daily, 10);
+        d.lockup.granularity = LockupGranularity::Seconds;
+        d.amount_scaled = 10 * 1_000_000;
+        let curr_ts = d.lockup.start_ts + days_to_secs(0.33);
+        let er = exchange_rate_entry(MAX_DAYS_LOCKED, 100);
+        assert_eq!(d.voting_power(&er, curr_ts)?, 18_923);
+        Ok(())
+    }
+
+    #[test]
+    pub fn vested_daily_secs() -> Result<()> {
+        let mut d = locked_deposit(LockupKind::Daily, 10);
+        d.lockup.granularity = LockupGranularity::Seconds;
+        let start_ts = d.lockup.start_ts;
+        assert_eq!(d.vested_at(start_ts + days_to_secs(2.5))?, 2_500);
+        assert_eq!(d.vested_at(start_ts + 1)?, 0);
+        assert_eq!(d.vested_at(start_ts + 87)?, 1);
+        assert_eq!(d.vested_at(start_ts + days_to_secs(10.0))?, 10_000);
+        Ok(())
+    }
+
+    #[test]
+    pub fn lockup_secs_partial_day() -> Result<()> {
+        let mut d = locked_deposit(LockupKind::Cliff, 1);
+        d.lockup.granularity = LockupGranularity::Seconds;
+        d.lockup.end_ts += days_to_secs(0.5);
+        assert_eq!(d.lockup.days_total()?, 1);
+        assert_eq!(d.lockup.days_left(d.lockup.start_ts)?, 1);
+        assert_eq!(d.lockup.secs_left(d.lockup.start_ts), 129_600);
+
+        d.lockup.granularity = LockupGranularity::Days;
+        assert_error(
+            d.lockup.days_total().map(|_| ()),
+            ErrorCode::InvalidLockupPeriod,
+        );
+        Ok(())
+    }
+
+    #[test]
+    pub fn lockup_bounds_unaligned() -> Result<()> {
+        let registrar = Registrar::zeroed();
+        assert_eq!(registrar.lockup_bounds(1_000, 500, 500)?, (1_000, 1_500));
+        Ok(())
+    }
+
+    #[test]
+    pub fn lockup_bounds_utc_midnight() -> Result<()> {
+        let mut registrar = Registrar::zeroed();
+        registrar.lockup_end_alignment = SECS_PER_DAY as u32;
+        // 2021-10-22T19:10:33Z, plus two days.
+        let curr_ts = 1634929833;
+        let max_lockup_secs = MAX_DAYS_LOCKED as i64 * SECS_PER_DAY;
+        let (start_ts, end_ts) =
+            registrar.lockup_bounds(curr_ts, 2 * SECS_PER_DAY, max_lockup_secs)?;
+        // 2021-10-25T00:00:00Z.
+        assert_eq!(end_ts, 1635120000);
+        assert_eq!(end_ts - start_ts, 2 * SECS_PER_DAY);
+        assert!(start_ts >= curr_ts);
+
+        // Already aligned lockups aren't shifted.
+        assert_eq!(
+            registrar.lockup_bounds(end_ts - SECS_PER_DAY, SECS_PER_DAY, max_lockup_secs)?,
+            (end_ts - SECS_PER_DAY, end_ts)
+        );
+        Ok(())
+    }
+
+    #[test]
+    pub fn lockup_bounds_no_lockup() -> Result<()> {
+        let mut registrar = Registrar::zeroed();
+        registrar.lockup_end_alignment = MAX_LOCKUP_END_ALIGNMENT;
+        let curr_ts = 1634929833;
+        assert_eq!(
+            registrar.lockup_bounds(curr_ts, 0, SECS_PER_DAY)?,
+            (curr_ts, curr_ts)
+        );
+        Ok(())
+    }
+
+    #[test]
+    pub fn lockup_bounds_aligned_past_max() {
+        let mut registrar = Registrar::zeroed();
+        registrar.lockup_end_alignment = MAX_LOCKUP_END_ALIGNMENT;
+        // A max lockup of two days, which alignment would extend by almost
+        // five hours.
+        let curr_ts = 1634929833;
+        assert_error(
+            registrar
+                .lockup_bounds(curr_ts, 2 * SECS_PER_DAY, 2 * SECS_PER_DAY)
+                .map(|_| ()),
+            ErrorCode::InvalidDays,
+        );
+        // A one day lockup still fits once aligned.
+        assert!(registrar
+            .lockup_bounds(curr_ts, SECS_PER_DAY, 2 * SECS_PER_DAY)
+            .is_ok());
+    }
+
+    #[test]
+    pub fn voting_power_before_aligned_start() -> Result<()> {
+        let d = locked_deposit(LockupKind::Daily, 10);
+        let er = exchange_rate_entry(MAX_DAYS_LOCKED, 100);
+        let start_ts = d.lockup.start_ts;
+        assert_eq!(
+            d.voting_power(&er, start_ts - 3_600)?,
+            d.voting_power(&er, start_ts)?
+        );
+        assert_eq!(d.vested_at(start_ts - 3_600)?, 0);
+        Ok(())
+    }
+
+    #[test]
+    pub fn isqrt_small() {
+        let roots = [0, 1, 1, 1, 2, 2, 2, 2, 2, 3];
+        for (n, root) in roots.iter().enumerate() {
+            assert_eq!(isqrt(n as u128), *root);
+        }
+    }
+
+    #[test]
+    pub fn isqrt_large() {
+        let max = u128::from(u64::MAX);
+        assert_eq!(isqrt(max), u128::from(u32::MAX));
+        assert_eq!(isqrt(max * max), max);
+        assert_eq!(isqrt(max * max - 1), max - 1);
+        assert_eq!(isqrt(u128::MAX), max);
+        for k in [1u128 << 32, 3_037_000_499, 999_999_999_999, max - 1].iter() {
+            assert_eq!(isqrt(k * k), *k);
+            assert_eq!(isqrt(k * k - 1), k - 1);
+            assert_eq!(isqrt(k * k + 2 * k), *k);
+        }
+    }
+
+    #[test]
+    pub fn aggregate_weight_linear() -> Result<()> {
+        let registrar = Registrar::zeroed();
+        assert_eq!(registrar.aggregate_weight(u64::MAX)?, u64::MAX);
+        Ok(())
+    }
+
+    #[test]
+    pub fn aggregate_weight_quadratic() -> Result<()> {
+        let mut registrar = Registrar::zeroed();
+        registrar.rate_decimals = 6;
+        registrar.weight_aggregation = WeightAggregation::Quadratic;
+        assert_eq!(registrar.aggregate_weight(4_000_000)?, 2_000_000);
+        assert_eq!(registrar.aggregate_weight(0)?, 0);
+        // sqrt((2^64 - 1) * 10^6), rounded down.
+        assert_eq!(registrar.aggregate_weight(u64::MAX)?, 4_294_967_295_999);
+        Ok(())
+    }
+
+    #[test]
+    pub fn cap_weight_unset() -> Result<()> {
+        let registrar = Registrar::zeroed();
+        assert_eq!(registrar.cap_weight(u64::MAX)?, u64::MAX);
+        Ok(())
+    }
+
+    #[test]
+    pub fn cap_weight_absolute() -> Result<()> {
+        let mut registrar = Registrar::zeroed();
+        registrar.voter_weight_cap.max_weight = 1_000;
+        assert_eq!(registrar.cap_weight(999)?, 999);
+        assert_eq!(registrar.cap_weight(5_000)?, 1_000);
+        Ok(())
+    }
+
+    #[test]
+    pub fn cap_weight_fraction() -> Result<()> {
+        let mut registrar = Registrar::zeroed();
+        registrar.voter_weight_cap.max_vote_weight_bps = 500;
+        assert_error(
+            registrar.cap_weight(1).map(|_| ()),
+            ErrorCode::MaxVoteWeightNotComputed,
+        );
+
+        registrar.max_vote_weight = u64::MAX;
+        assert_eq!(registrar.cap_weight(u64::MAX)?, u64::MAX / 20);
+
+        // The lower of both limits applies.
+        registrar.voter_weight_cap.max_weight = 1_000;
+        assert_eq!(registrar.cap_weight(u64::MAX)?, 1_000);
+        Ok(())
+    }
+
+    #[test]
+    pub fn extend_lockup_cliff_before_end() -> Result<()> {
+        let mut d = locked_deposit(LockupKind::Cliff, 10);
+        let curr_ts = d.lockup.start_ts + days_to_secs(9.5);
+        let vested_before = d.vested_at(curr_ts)?;
+        d.extend_lockup(5, LockupKind::Cliff, curr_ts)?;
+        assert_eq!({ d.lockup.end_ts }, d.lockup.start_ts + days_to_secs(15.0));
+        assert_eq!(d.lockup.days_left(curr_ts)?, 6);
+        assert_vested_never_decreases(&d, curr_ts, vested_before)
+    }
+
+    #[test]
+    pub fn extend_lockup_daily_to_cliff_before_first_day() -> Result<()> {
+        let mut d = locked_deposit(LockupKind::Daily, 10);
+        let curr_ts = d.lockup.start_ts + days_to_secs(0.5);
+        let vested_before = d.vested_at(curr_ts)?;
+        d.extend_lockup(20, LockupKind::Cliff, curr_ts)?;
+        assert!(matches!(d.lockup.kind, LockupKind::Cliff));
+        assert_eq!(d.lockup.days_total()?, 30);
+        assert_vested_never_decreases(&d, curr_ts, vested_before)
+    }
+
+    #[test]
+    pub fn extend_lockup_daily_partially_vested() -> Result<()> {
+        let mut d = locked_deposit(LockupKind::Daily, 10);
+        let end_ts = d.lockup.end_ts;
+        let curr_ts = d.lockup.start_ts + days_to_secs(3.5);
+        let vested_before = d.vested_at(curr_ts)?;
+        assert_eq!(vested_before, 3_000);
+        d.extend_lockup(5, LockupKind::Daily, curr_ts)?;
+        // The start moves back 3 * 5 / 7 days, rounded up to 3, so 6 of 18
+        // days have vested instead of 3 of 10.
+        assert_eq!({ d.lockup.end_ts }, end_ts + days_to_secs(5.0));
+        assert_eq!(d.lockup.days_total()?, 18);
+        assert_eq!(d.lockup.days_left(curr_ts)?, 12);
+        assert_eq!(d.vested_at(curr_ts)?, 10_000 * 6 / 18);
+        assert_vested_never_decreases(&d, curr_ts, vested_before)
+    }
+
+    #[test]
+    pub fn extend_lockup_daily_partially_vested_seconds() -> Result<()> {
+        let mut d = locked_deposit(LockupKind::Daily, 10);
+        d.lockup.granularity = LockupGranularity::Seconds;
+        let curr_ts = d.lockup.start_ts + days_to_secs(3.5);
+        let vested_before = d.vested_at(curr_ts)?;
+        d.extend_lockup(5, LockupKind::Daily, curr_ts)?;
+        assert_eq!(d.lockup.secs_left(curr_ts), days_to_secs(11.5) as u64);
+        assert!(d.vested_at(curr_ts)? >= vested_before);
+        assert_vested_never_decreases(&d, curr_ts, vested_before)
+    }
+
+    #[test]
+    pub fn extend_lockup_daily_to_cliff_partially_vested() {
+        let mut d = locked_deposit(LockupKind::Daily, 10);
+        let curr_ts = d.lockup.start_ts + days_to_secs(3.0);
+        assert_error(
+            d.extend_lockup(1, LockupKind::Cliff, curr_ts),
+            ErrorCode::VestedAmountDecrease,
+        );
+        assert_eq!(d.lockup.days_total().unwrap(), 10);
+    }
+
+    #[test]
+    pub fn extend_lockup_daily_after_end() {
+        let mut d = locked_deposit(LockupKind::Daily, 10);
+        let curr_ts = d.lockup.start_ts + days_to_secs(10.0);
+        assert_error(
+            d.extend_lockup(1, LockupKind::Daily, curr_ts),
+            ErrorCode::VestedAmountDecrease,
+        );
+    }
+
+    #[test]
+    pub fn extend_lockup_cliff_after_end() {
+        let mut d = locked_deposit(LockupKind::Cliff, 10);
+        let curr_ts = d.lockup.start_ts + days_to_secs(10.0);
+        assert_error(
+            d.extend_lockup(1, LockupKind::Cliff, curr_ts),
+            ErrorCode::VestedAmountDecrease,
+        );
+    }
+
+    #[test]
+    pub fn extend_lockup_cliff_to_daily() {
+        let mut d = locked_deposit(LockupKind::Cliff, 10);
+        assert_error(
+            d.extend_lockup(1, LockupKind::Daily, d.lockup.start_ts),
+            ErrorCode::InvalidLockupKind,
+        );
+    }
+
+    #[test]
+    pub fn reset_lockup_cliff_before_end() -> Result<()> {
+        let mut d = locked_deposit(LockupKind::Cliff, 10);
+        let curr_ts = d.lockup.start_ts + days_to_secs(9.5);
+        let vested_before = d.vested_at(curr_ts)?;
+        d.reset_lockup(20, curr_ts)?;
+        assert_eq!({ d.lockup.start_ts }, curr_ts);
+        assert_eq!(d.lockup.days_left(curr_ts)?, 20);
+        assert_vested_never_decreases(&d, curr_ts, vested_before)
+    }
+
+    #[test]
+    pub fn reset_lockup_daily_partially_vested() {
+        let mut d = locked_deposit(LockupKind::Daily, 10);
+        let start_ts = d.lockup.start_ts;
+        let curr_ts = start_ts + days_to_secs(3.0);
+        assert_error(d.reset_lockup(20, curr_ts), ErrorCode::VestedAmountDecrease);
+        assert_eq!({ d.lockup.start_ts }, start_ts);
+    }
+
+    #[test]
+    pub fn reset_lockup_cliff_after_end() {
+        let mut d = locked_deposit(LockupKind::Cliff, 10);
+        let curr_ts = d.lockup.start_ts + days_to_secs(11.0);
+        assert_error(d.reset_lockup(20, curr_ts), ErrorCode::VestedAmountDecrease);
+    }
+
+    #[test]
+    pub fn weight_policy_default_includes_all() -> Result<()> {
+        run_test_weight_policy(TestWeightPolicy {
+            expected_included: true,
+            policy: WeightPolicy::default(),
+            rate_idx: 1,
+            days_total: 10.0,
+            curr_day: 9.5,
+        })
+    }
+
+    #[test]
+    pub fn weight_policy_min_lockup_days_met() -> Result<()> {
+        run_test_weight_policy(TestWeightPolicy {
+            expected_included: true,
+            policy: WeightPolicy {
+                min_lockup_days: 5,
+                excluded_rates: 0,
+            },
+            rate_idx: 0,
+            days_total: 10.0,
+            curr_day: 5.5,
+        })
+    }
+
+    #[test]
+    pub fn weight_policy_min_lockup_days_not_met() -> Result<()> {
+        run_test_weight_policy(TestWeightPolicy {
+            expected_included: false,
+            policy: WeightPolicy {
+                min_lockup_days: 5,
+                excluded_rates: 0,
+            },
+            rate_idx: 0,
+            days_total: 10.0,
+            curr_day: 6.0,
+        })
+    }
+
+    #[test]
+    pub fn weight_policy_excluded_rate() -> Result<()> {
+        run_test_weight_policy(TestWeightPolicy {
+            expected_included: false,
+            policy: WeightPolicy {
+                min_lockup_days: 0,
+                excluded_rates: 0b10,
+            },
+            rate_idx: 1,
+            days_total: 10.0,
+            curr_day: 0.0,
+        })
+    }
+
+    struct TestDaysLeft {
+        expected_days_left: u64,
+        days_total: f64,
+        curr_day: f64,
+    }
+
+    struct TestVotingPower {
         amount_deposited: u64,
         days_total: f64,
         curr_day: f64,
@@ -739,6 +2838,23 @@ mod tests {
         kind: LockupKind,
     }
 
+    struct TestConvert {
+        expected_converted: u64,
+        amount: u64,
+        rate: u64,
+        rate_denominator: u64,
+        decimals: u8,
+        rate_decimals: u8,
+    }
+
+    struct TestWeightPolicy {
+        expected_included: bool,
+        policy: WeightPolicy,
+        rate_idx: u8,
+        days_total: f64,
+        curr_day: f64,
+    }
+
     fn run_test_days_left(t: TestDaysLeft) -> Result<()> {
         let start_ts = 1634929833;
         let end_ts = start_ts + days_to_secs(t.days_total);
@@ -747,7 +2863,8 @@ mod tests {
             kind: LockupKind::Cliff,
             start_ts,
             end_ts,
-            padding: [0u8; 16],
+            granularity: LockupGranularity::Days,
+            padding: [0u8; 15],
         };
         let days_left = l.days_left(curr_ts)?;
         assert_eq!(days_left, t.expected_days_left);
@@ -755,6 +2872,10 @@ mod tests {
     }
 
     fn run_test_voting_power(t: TestVotingPower) -> Result<()> {
+        run_test_voting_power_with_rate(t, exchange_rate_entry(MAX_DAYS_LOCKED, 100))
+    }
+
+    fn run_test_voting_power_with_rate(t: TestVotingPower, er: ExchangeRateEntry) -> Result<()> {
         let start_ts = 1634929833;
         let end_ts = start_ts + days_to_secs(t.days_total);
         let d = DepositEntry {
@@ -767,15 +2888,151 @@ mod tests {
                 start_ts,
                 end_ts,
                 kind: t.kind,
-                padding: [0u8; 16],
+                granularity: LockupGranularity::Days,
+                padding: [0u8; 15],
             },
         };
         let curr_ts = start_ts + days_to_secs(t.curr_day);
-        let power = d.voting_power(curr_ts)?;
+        let power = d.voting_power(&er, curr_ts)?;
         assert_eq!(power, t.expected_voting_power);
         Ok(())
     }
 
+    fn run_test_convert(t: TestConvert) -> Result<()> {
+        let mut registrar = Registrar::zeroed();
+        registrar.rate_decimals = t.rate_decimals;
+        let er = ExchangeRateEntry {
+            rate: t.rate,
+            rate_denominator: t.rate_denominator,
+            decimals: t.decimals,
+            ..exchange_rate_entry(MAX_DAYS_LOCKED, 100)
+        };
+        assert_eq!(registrar.convert(&er, t.amount)?, t.expected_converted);
+        Ok(())
+    }
+
+    fn registrar_with_two_rates() -> (Registrar, Pubkey, Pubkey) {
+        let (a, b) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let mut registrar = Registrar::zeroed();
+        registrar.rates[0] = ExchangeRateEntry {
+            mint: a,
+            ..exchange_rate_entry(MAX_DAYS_LOCKED, 100)
+        };
+        registrar.rates[1] = ExchangeRateEntry {
+            mint: b,
+            ..exchange_rate_entry(MAX_DAYS_LOCKED, 100)
+        };
+        (registrar, a, b)
+    }
+
+    fn locked_deposit(kind: LockupKind, days: u64) -> DepositEntry {
+        let start_ts = 1634929833;
+        DepositEntry {
+            is_used: true,
+            rate_idx: 0,
+            amount_deposited: 10_000,
+            amount_withdrawn: 0,
+            amount_scaled: 10_000,
+            lockup: Lockup {
+                kind,
+                start_ts,
+                end_ts: start_ts + days_to_secs(days as f64),
+                granularity: LockupGranularity::Days,
+                padding: [0u8; 15],
+            },
+        }
+    }
+
+    // Checks that `d` vests monotonically from `vested_before` at `from_ts`,
+    // in hourly steps until after the end of its lockup.
+    fn assert_vested_never_decreases(
+        d: &DepositEntry,
+        from_ts: i64,
+        vested_before: u64,
+    ) -> Result<()> {
+        let mut prev = vested_before;
+        let mut curr_ts = from_ts;
+        while curr_ts <= d.lockup.end_ts + SECS_PER_DAY {
+            let vested = d.vested_at(curr_ts)?;
+            assert!(vested >= prev);
+            prev = vested;
+            curr_ts += 3_600;
+        }
+        assert_eq!(prev, { d.amount_deposited });
+        Ok(())
+    }
+
+    fn assert_error(result: Result<()>, expected: ErrorCode) {
+        assert_eq!(result.unwrap_err(), ProgramError::from(expected));
+    }
+
+    fn run_test_max_voting_power(mode: MaxVoteWeightMode, expected: u64) -> Result<()> {
+        let mut registrar = Registrar::zeroed();
+        registrar.rate_decimals = 6;
+        registrar.max_vote_weight_mode = mode;
+        let er = exchange_rate_entry(365, 250);
+        assert_eq!(registrar.max_voting_power(&er, 1_000)?, expected);
+        Ok(())
+    }
+
+    fn run_test_weight_policy(t: TestWeightPolicy) -> Result<()> {
+        let start_ts = 1634929833;
+        let end_ts = start_ts + days_to_secs(t.days_total);
+        let d = DepositEntry {
+            is_used: true,
+            rate_idx: t.rate_idx,
+            amount_deposited: 10 * 1_000_000,
+            amount_withdrawn: 0,
+            amount_scaled: 10 * 1_000_000,
+            lockup: Lockup {
+                start_ts,
+                end_ts,
+                kind: LockupKind::Cliff,
+                granularity: LockupGranularity::Days,
+                padding: [0u8; 15],
+            },
+        };
+        let curr_ts = start_ts + days_to_secs(t.curr_day);
+        assert_eq!(t.policy.includes(&d, curr_ts)?, t.expected_included);
+        Ok(())
+    }
+
+    fn exchange_rate_entry(max_lockup_days: u64, max_boost_pct: u64) -> ExchangeRateEntry {
+        ExchangeRateEntry {
+            mint: Pubkey::default(),
+            rate: 1,
+            rate_denominator: 1,
+            decimals: 6,
+            max_lockup_days,
+            max_boost_pct,
+            oracle: Pubkey::default(),
+            min_rate: 0,
+            max_rate: 0,
+            max_staleness_slots: 0,
+            last_update_slot: 0,
+        }
+    }
+
+    fn oracle_exchange_rate_entry() -> ExchangeRateEntry {
+        ExchangeRateEntry {
+            rate: 10,
+            oracle: Pubkey::new_unique(),
+            min_rate: 5,
+            max_rate: 20,
+            max_staleness_slots: 10,
+            last_update_slot: 0,
+            ..exchange_rate_entry(MAX_DAYS_LOCKED, 100)
+        }
+    }
+
+    fn price_feed(rate: u64, publish_slot: u64) -> PriceFeed {
+        PriceFeed {
+            magic: PRICE_FEED_MAGIC,
+            rate,
+            publish_slot,
+        }
+    }
+
     fn days_to_secs(days: f64) -> i64 {
         let d = 86_400.0 * days;
         d.round() as i64
@@ -787,10 +3044,15 @@ mod tests {
     // deposit - the amount locked up
     // days - the number of days locked
     fn locked_daily_power(amount: u64, days: u64) -> u64 {
+        locked_daily_power_with(amount, days, MAX_DAYS_LOCKED, 100)
+    }
+
+    // Same as `locked_daily_power`, with a per-mint max lockup and boost.
+    fn locked_daily_power_with(amount: u64, days: u64, max_days: u64, boost_pct: u64) -> u64 {
         let mut total = 0f64;
         for k in 1..(days + 1) {
-            total += (k as f64 * amount as f64) / (MAX_DAYS_LOCKED as f64 * days as f64)
+            total += (k as f64 * amount as f64) / (max_days as f64 * days as f64)
         }
-        total.floor() as u64
+        (total * (boost_pct as f64 / 100.0)).floor() as u64
     }
 }
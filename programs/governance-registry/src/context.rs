@@ -1,26 +1,31 @@
 use crate::account::*;
+use crate::error::*;
 use anchor_lang::prelude::*;
-use anchor_spl::associated_token::AssociatedToken;
+use anchor_spl::associated_token::{self, AssociatedToken};
 use anchor_spl::token::{self, Mint, Token, TokenAccount};
 use std::mem::size_of;
 
 pub const VOTER_WEIGHT_RECORD: [u8; 19] = *b"voter-weight-record";
+pub const VOTING_MINT: [u8; 11] = *b"voting-mint";
 
 #[derive(Accounts)]
-#[instruction(rate_decimals: u8, registrar_bump: u8)]
+#[instruction(rate_decimals: u8, registrar_bump: u8, voting_token_mode: VotingTokenMode)]
 pub struct CreateRegistrar<'info> {
     #[account(
         init,
-        seeds = [realm.key().as_ref()],
+        seeds = [realm.key().as_ref(), realm_governing_token_mint.key().as_ref()],
         bump = registrar_bump,
         payer = payer,
         space = 8 + size_of::<Registrar>()
     )]
     pub registrar: AccountLoader<'info, Registrar>,
-    // Unsafe and untrusted. This instruction needs to be invoked immediatley
-    // after the realm is created.
+    // Checked to be a realm owned by `governance_program_id`, using
+    // `realm_governing_token_mint` and `realm_authority`.
     pub realm: UncheckedAccount<'info>,
-    pub realm_community_mint: Account<'info, Mint>,
+    // Checked to be `governance_program::ID`.
+    pub governance_program_id: UncheckedAccount<'info>,
+    pub realm_governing_token_mint: Account<'info, Mint>,
+    pub realm_authority: Signer<'info>,
     pub authority: UncheckedAccount<'info>,
     pub payer: Signer<'info>,
     pub system_program: Program<'info, System>,
@@ -28,6 +33,13 @@ pub struct CreateRegistrar<'info> {
     pub rent: Sysvar<'info, Rent>,
 }
 
+#[derive(Accounts)]
+pub struct UpdateRegistrar<'info> {
+    #[account(mut, has_one = authority)]
+    pub registrar: AccountLoader<'info, Registrar>,
+    pub authority: Signer<'info>,
+}
+
 #[derive(Accounts)]
 #[instruction(voter_bump: u8, voter_weight_record_bump: u8)]
 pub struct CreateVoter<'info> {
@@ -36,9 +48,9 @@ pub struct CreateVoter<'info> {
         seeds = [registrar.key().as_ref(), authority.key().as_ref()],
         bump = voter_bump,
         payer = authority,
-        space = 8 + size_of::<Voter>(),
+        space = Voter::space(VOTER_INITIAL_CAPACITY),
     )]
-    pub voter: AccountLoader<'info, Voter>,
+    pub voter: Box<Account<'info, Voter>>,
     #[account(
         init,
         seeds = [VOTER_WEIGHT_RECORD.as_ref(), registrar.key().as_ref(), authority.key().as_ref()],
@@ -61,32 +73,75 @@ pub struct CreateVoter<'info> {
 pub struct CreateExchangeRate<'info> {
     #[account(
         init,
-        payer = authority,
+        payer = payer,
         associated_token::authority = registrar,
         associated_token::mint = deposit_mint,
     )]
     pub exchange_vault: Account<'info, TokenAccount>,
+    pub deposit_mint: Account<'info, Mint>,
+    #[account(mut, has_one = authority)]
+    pub registrar: AccountLoader<'info, Registrar>,
+    pub authority: Signer<'info>,
+    // Separate from `authority`, which can be a governance account that
+    // can't pay for the new accounts.
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub rent: Sysvar<'info, Rent>,
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CreateRateVotingMint<'info> {
     #[account(
         init,
         seeds = [registrar.key().as_ref(), deposit_mint.key().as_ref()],
         bump,
-        payer = authority,
+        payer = payer,
         mint::authority = registrar,
         mint::freeze_authority = registrar,
         mint::decimals = deposit_mint.decimals,
     )]
     pub voting_mint: Account<'info, Mint>,
+    // Checked to have an exchange rate.
     pub deposit_mint: Account<'info, Mint>,
-    #[account(mut, has_one = authority)]
     pub registrar: AccountLoader<'info, Registrar>,
     #[account(mut)]
-    pub authority: Signer<'info>,
+    pub payer: Signer<'info>,
     pub rent: Sysvar<'info, Rent>,
     pub token_program: Program<'info, Token>,
-    pub associated_token_program: Program<'info, AssociatedToken>,
     pub system_program: Program<'info, System>,
 }
 
+#[derive(Accounts)]
+pub struct CreateVotingMint<'info> {
+    #[account(
+        init,
+        seeds = [VOTING_MINT.as_ref(), registrar.key().as_ref()],
+        bump,
+        payer = payer,
+        mint::authority = registrar,
+        mint::freeze_authority = registrar,
+        mint::decimals = registrar.load()?.rate_decimals,
+    )]
+    pub voting_mint: Account<'info, Mint>,
+    pub registrar: AccountLoader<'info, Registrar>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub rent: Sysvar<'info, Rent>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct RefreshExchangeRate<'info> {
+    #[account(mut)]
+    pub registrar: AccountLoader<'info, Registrar>,
+    // Checked against the oracle configured in the exchange rate entry.
+    pub price_feed: UncheckedAccount<'info>,
+}
+
 #[derive(Accounts)]
 pub struct CreateDeposit<'info> {
     pub deposit: UpdateDeposit<'info>,
@@ -94,9 +149,10 @@ pub struct CreateDeposit<'info> {
 
 #[derive(Accounts)]
 pub struct UpdateDeposit<'info> {
+    #[account(mut)]
     pub registrar: AccountLoader<'info, Registrar>,
     #[account(mut, has_one = authority, has_one = registrar)]
-    pub voter: AccountLoader<'info, Voter>,
+    pub voter: Box<Account<'info, Voter>>,
     #[account(
         mut,
         associated_token::authority = registrar,
@@ -108,21 +164,10 @@ pub struct UpdateDeposit<'info> {
         constraint = deposit_token.mint == deposit_mint.key(),
     )]
     pub deposit_token: Account<'info, TokenAccount>,
-    #[account(
-        init_if_needed,
-        payer = authority,
-        associated_token::authority = authority,
-        associated_token::mint = voting_mint,
-    )]
-    pub voting_token: Account<'info, TokenAccount>,
+    // Pays for the voting token account, if it doesn't exist yet.
+    #[account(mut)]
     pub authority: Signer<'info>,
     pub deposit_mint: Account<'info, Mint>,
-    #[account(
-        mut,
-        seeds = [registrar.key().as_ref(), deposit_token.mint.as_ref()],
-        bump,
-    )]
-    pub voting_mint: Account<'info, Mint>,
     pub token_program: Program<'info, Token>,
     pub associated_token_program: Program<'info, AssociatedToken>,
     pub system_program: Program<'info, System>,
@@ -140,42 +185,36 @@ impl<'info> UpdateDeposit<'info> {
         CpiContext::new(program, accounts)
     }
 
-    pub fn thaw_ctx(&self) -> CpiContext<'_, '_, '_, 'info, token::ThawAccount<'info>> {
-        let program = self.token_program.to_account_info();
-        let accounts = token::ThawAccount {
-            account: self.voting_token.to_account_info(),
-            mint: self.voting_mint.to_account_info(),
-            authority: self.registrar.to_account_info(),
-        };
-        CpiContext::new(program, accounts)
-    }
-
-    pub fn mint_to_ctx(&self) -> CpiContext<'_, '_, '_, 'info, token::MintTo<'info>> {
-        let program = self.token_program.to_account_info();
-        let accounts = token::MintTo {
-            mint: self.voting_mint.to_account_info(),
-            to: self.voting_token.to_account_info(),
-            authority: self.registrar.to_account_info(),
-        };
-        CpiContext::new(program, accounts)
-    }
-
-    pub fn freeze_ctx(&self) -> CpiContext<'_, '_, '_, 'info, token::FreezeAccount<'info>> {
-        let program = self.token_program.to_account_info();
-        let accounts = token::FreezeAccount {
-            account: self.voting_token.to_account_info(),
-            mint: self.voting_mint.to_account_info(),
-            authority: self.registrar.to_account_info(),
+    /// Creates the authority's associated token account of `voting_mint`,
+    /// unless it already exists.
+    pub fn create_voting_token(
+        &self,
+        voting_mint: &AccountInfo<'info>,
+        voting_token: &AccountInfo<'info>,
+    ) -> Result<()> {
+        if !voting_token.data_is_empty() {
+            return Ok(());
+        }
+        let program = self.associated_token_program.to_account_info();
+        let accounts = associated_token::Create {
+            payer: self.authority.to_account_info(),
+            associated_token: voting_token.clone(),
+            authority: self.authority.to_account_info(),
+            mint: voting_mint.clone(),
+            system_program: self.system_program.to_account_info(),
+            token_program: self.token_program.to_account_info(),
+            rent: self.rent.to_account_info(),
         };
-        CpiContext::new(program, accounts)
+        associated_token::create(CpiContext::new(program, accounts))
     }
 }
 
 #[derive(Accounts)]
 pub struct Withdraw<'info> {
+    #[account(mut)]
     pub registrar: AccountLoader<'info, Registrar>,
     #[account(mut, has_one = registrar, has_one = authority)]
-    pub voter: AccountLoader<'info, Voter>,
+    pub voter: Box<Account<'info, Voter>>,
     #[account(
         mut,
         associated_token::authority = registrar,
@@ -183,18 +222,6 @@ pub struct Withdraw<'info> {
     )]
     pub exchange_vault: Account<'info, TokenAccount>,
     pub withdraw_mint: Account<'info, Mint>,
-    #[account(
-        mut,
-        associated_token::authority = authority,
-        associated_token::mint = voting_mint,
-    )]
-    pub voting_token: Account<'info, TokenAccount>,
-    #[account(
-        mut,
-        seeds = [registrar.load()?.realm.as_ref(), voting_token.mint.as_ref()],
-        bump,
-    )]
-    pub voting_mint: Account<'info, Mint>,
     #[account(mut)]
     pub destination: Account<'info, TokenAccount>,
     pub authority: Signer<'info>,
@@ -211,33 +238,146 @@ impl<'info> Withdraw<'info> {
         };
         CpiContext::new(program, accounts)
     }
+}
 
-    pub fn thaw_ctx(&self) -> CpiContext<'_, '_, '_, 'info, token::ThawAccount<'info>> {
-        let program = self.token_program.to_account_info();
+/// Voting token accounts, passed as remaining accounts to deposit and
+/// withdraw instructions when the registrar issues voting tokens.
+pub struct VotingTokenAccounts<'info> {
+    pub voting_mint: Account<'info, Mint>,
+    pub voting_token: Account<'info, TokenAccount>,
+}
+
+impl<'info> VotingTokenAccounts<'info> {
+    /// Loads the voting mint used for `deposit_mint` and `authority`'s voting
+    /// token account from the given remaining accounts, in that order.
+    pub fn load(
+        program_id: &Pubkey,
+        mode: VotingTokenMode,
+        registrar: &Pubkey,
+        deposit_mint: &Pubkey,
+        authority: &Pubkey,
+        remaining_accounts: &[AccountInfo<'info>],
+    ) -> Result<Self> {
+        require!(remaining_accounts.len() >= 2, VotingTokenAccountsMissing);
+
+        let (voting_mint_address, _) = match mode {
+            VotingTokenMode::Scaled => Pubkey::find_program_address(
+                &[VOTING_MINT.as_ref(), registrar.as_ref()],
+                program_id,
+            ),
+            _ => Pubkey::find_program_address(
+                &[registrar.as_ref(), deposit_mint.as_ref()],
+                program_id,
+            ),
+        };
+        let voting_mint = Account::<Mint>::try_from(&remaining_accounts[0])?;
+        if voting_mint.key() != voting_mint_address {
+            msg!(
+                "voting mint {} doesn't match expected {}",
+                voting_mint.key(),
+                voting_mint_address
+            );
+            return Err(ErrorCode::InvalidVotingMint.into());
+        }
+
+        let voting_token = Account::<TokenAccount>::try_from(&remaining_accounts[1])?;
+        require!(voting_token.mint == voting_mint_address, InvalidVotingToken);
+        require!(voting_token.owner == *authority, InvalidVotingToken);
+
+        Ok(Self {
+            voting_mint,
+            voting_token,
+        })
+    }
+
+    /// Mints or burns *frozen* voting tokens to move a deposit's share of the
+    /// voting token balance from `before` to `after`.
+    pub fn update_balance(
+        &self,
+        token_program: &impl ToAccountInfo<'info>,
+        registrar: &impl ToAccountInfo<'info>,
+        authority: &impl ToAccountInfo<'info>,
+        registrar_seeds: &[&[&[u8]]],
+        before: u64,
+        after: u64,
+    ) -> Result<()> {
+        // Thaw the account if it's frozen, so that we can mint or burn.
+        if self.voting_token.is_frozen() {
+            token::thaw_account(
+                self.thaw_ctx(token_program, registrar)
+                    .with_signer(registrar_seeds),
+            )?;
+        }
+
+        if after > before {
+            token::mint_to(
+                self.mint_to_ctx(token_program, registrar)
+                    .with_signer(registrar_seeds),
+                after - before,
+            )?;
+        } else if before > after {
+            token::burn(self.burn_ctx(token_program, authority), before - after)?;
+        }
+
+        // Freeze the vote tokens; they are just used for UIs + accounting.
+        token::freeze_account(
+            self.freeze_ctx(token_program, registrar)
+                .with_signer(registrar_seeds),
+        )
+    }
+
+    pub fn thaw_ctx(
+        &self,
+        token_program: &impl ToAccountInfo<'info>,
+        registrar: &impl ToAccountInfo<'info>,
+    ) -> CpiContext<'_, '_, '_, 'info, token::ThawAccount<'info>> {
+        let program = token_program.to_account_info();
         let accounts = token::ThawAccount {
             account: self.voting_token.to_account_info(),
             mint: self.voting_mint.to_account_info(),
-            authority: self.registrar.to_account_info(),
+            authority: registrar.to_account_info(),
         };
         CpiContext::new(program, accounts)
     }
 
-    pub fn burn_ctx(&self) -> CpiContext<'_, '_, '_, 'info, token::Burn<'info>> {
-        let program = self.token_program.to_account_info();
+    pub fn mint_to_ctx(
+        &self,
+        token_program: &impl ToAccountInfo<'info>,
+        registrar: &impl ToAccountInfo<'info>,
+    ) -> CpiContext<'_, '_, '_, 'info, token::MintTo<'info>> {
+        let program = token_program.to_account_info();
+        let accounts = token::MintTo {
+            mint: self.voting_mint.to_account_info(),
+            to: self.voting_token.to_account_info(),
+            authority: registrar.to_account_info(),
+        };
+        CpiContext::new(program, accounts)
+    }
+
+    pub fn burn_ctx(
+        &self,
+        token_program: &impl ToAccountInfo<'info>,
+        authority: &impl ToAccountInfo<'info>,
+    ) -> CpiContext<'_, '_, '_, 'info, token::Burn<'info>> {
+        let program = token_program.to_account_info();
         let accounts = token::Burn {
             mint: self.voting_mint.to_account_info(),
             to: self.voting_token.to_account_info(),
-            authority: self.authority.to_account_info(),
+            authority: authority.to_account_info(),
         };
         CpiContext::new(program, accounts)
     }
 
-    pub fn freeze_ctx(&self) -> CpiContext<'_, '_, '_, 'info, token::FreezeAccount<'info>> {
-        let program = self.token_program.to_account_info();
+    pub fn freeze_ctx(
+        &self,
+        token_program: &impl ToAccountInfo<'info>,
+        registrar: &impl ToAccountInfo<'info>,
+    ) -> CpiContext<'_, '_, '_, 'info, token::FreezeAccount<'info>> {
+        let program = token_program.to_account_info();
         let accounts = token::FreezeAccount {
             account: self.voting_token.to_account_info(),
             mint: self.voting_mint.to_account_info(),
-            authority: self.registrar.to_account_info(),
+            authority: registrar.to_account_info(),
         };
         CpiContext::new(program, accounts)
     }
@@ -245,44 +385,182 @@ impl<'info> Withdraw<'info> {
 
 #[derive(Accounts)]
 pub struct UpdateSchedule<'info> {
-    #[account(mut, has_one = authority)]
-    pub voter: AccountLoader<'info, Voter>,
+    pub registrar: AccountLoader<'info, Registrar>,
+    #[account(mut, has_one = authority, has_one = registrar)]
+    pub voter: Box<Account<'info, Voter>>,
     pub authority: Signer<'info>,
 }
 
 #[derive(Accounts)]
 pub struct UpdateVoterWeightRecord<'info> {
     pub registrar: AccountLoader<'info, Registrar>,
-    #[account(
-        has_one = registrar,
-        has_one = authority,
-    )]
-    pub voter: AccountLoader<'info, Voter>,
+    #[account(mut, has_one = registrar)]
+    pub voter: Box<Account<'info, Voter>>,
     #[account(
         mut,
-        seeds = [VOTER_WEIGHT_RECORD.as_ref(), registrar.key().as_ref(), authority.key().as_ref()],
-        bump = voter.load()?.voter_weight_record_bump,
+        seeds = [VOTER_WEIGHT_RECORD.as_ref(), registrar.key().as_ref(), voter.authority.as_ref()],
+        bump = voter.voter_weight_record_bump,
         constraint = voter_weight_record.realm == registrar.load()?.realm,
-        constraint = voter_weight_record.governing_token_owner == voter.load()?.authority,
+        constraint = voter_weight_record.governing_token_owner == voter.authority,
     )]
     pub voter_weight_record: Account<'info, VoterWeightRecord>,
-    pub authority: Signer<'info>,
     pub system_program: Program<'info, System>,
 }
 
-// Remaining accounts should all the token mints that have registered
-// exchange rates.
+// Remaining accounts are `(Voter, VoterWeightRecord)` pairs, see
+// `update_voter_weight_records`.
+#[derive(Accounts)]
+pub struct UpdateVoterWeightRecords<'info> {
+    pub registrar: AccountLoader<'info, Registrar>,
+}
+
+// Remaining accounts should be the token mints of all registered exchange
+// rates, in the order of `Registrar::rates`. They're unused when the max vote
+// weight is computed from the registrar's deposit totals.
 #[derive(Accounts)]
 pub struct UpdateMaxVoteWeight<'info> {
+    #[account(mut)]
     pub registrar: AccountLoader<'info, Registrar>,
     // TODO: SPL governance has not yet implemented this.
     pub max_vote_weight_record: UncheckedAccount<'info>,
 }
 
+#[derive(Accounts)]
+pub struct MigrateVoter<'info> {
+    // Read from any layout version, see `Voter::from_any_version`.
+    #[account(mut, owner = crate::ID)]
+    pub voter: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(voter_bump: u8, capacity: u8)]
+pub struct GrowVoter<'info> {
+    #[account(mut, has_one = authority, has_one = registrar, close = authority)]
+    pub voter: Box<Account<'info, Voter>>,
+    #[account(
+        init,
+        seeds = [registrar.key().as_ref(), authority.key().as_ref(), &[capacity]],
+        bump = voter_bump,
+        payer = payer,
+        space = Voter::space(capacity),
+    )]
+    pub new_voter: Box<Account<'info, Voter>>,
+    pub registrar: AccountLoader<'info, Registrar>,
+    // Receives the rent of the old voter.
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+#[instruction(registrar_bump: u8)]
+pub struct MigrateUnversionedRegistrar<'info> {
+    // Checked to hold an unversioned registrar of `realm`, `authority` and
+    // `realm_governing_token_mint`.
+    #[account(owner = crate::ID)]
+    pub unversioned_registrar: UncheckedAccount<'info>,
+    #[account(
+        init,
+        seeds = [realm.key().as_ref(), realm_governing_token_mint.key().as_ref()],
+        bump = registrar_bump,
+        payer = payer,
+        space = 8 + size_of::<Registrar>()
+    )]
+    pub registrar: AccountLoader<'info, Registrar>,
+    pub realm: UncheckedAccount<'info>,
+    pub realm_governing_token_mint: Account<'info, Mint>,
+    pub authority: Signer<'info>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateUnversionedExchangeVault<'info> {
+    // Checked to hold an unversioned registrar of the same realm and mint as
+    // `registrar`.
+    #[account(owner = crate::ID)]
+    pub unversioned_registrar: UncheckedAccount<'info>,
+    pub registrar: AccountLoader<'info, Registrar>,
+    #[account(
+        mut,
+        associated_token::authority = unversioned_registrar,
+        associated_token::mint = deposit_mint,
+    )]
+    pub unversioned_exchange_vault: Account<'info, TokenAccount>,
+    #[account(
+        init,
+        payer = payer,
+        associated_token::authority = registrar,
+        associated_token::mint = deposit_mint,
+    )]
+    pub exchange_vault: Account<'info, TokenAccount>,
+    pub deposit_mint: Account<'info, Mint>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub rent: Sysvar<'info, Rent>,
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> MigrateUnversionedExchangeVault<'info> {
+    pub fn transfer_ctx(&self) -> CpiContext<'_, '_, '_, 'info, token::Transfer<'info>> {
+        let program = self.token_program.to_account_info();
+        let accounts = token::Transfer {
+            from: self.unversioned_exchange_vault.to_account_info(),
+            to: self.exchange_vault.to_account_info(),
+            authority: self.unversioned_registrar.to_account_info(),
+        };
+        CpiContext::new(program, accounts)
+    }
+}
+
+#[derive(Accounts)]
+#[instruction(voter_bump: u8, voter_weight_record_bump: u8)]
+pub struct MigrateUnversionedVoter<'info> {
+    // Checked to hold an unversioned voter of `authority` and
+    // `unversioned_registrar`.
+    #[account(mut, owner = crate::ID)]
+    pub unversioned_voter: UncheckedAccount<'info>,
+    // Checked to hold an unversioned registrar of the same realm and mint as
+    // `registrar`.
+    #[account(owner = crate::ID)]
+    pub unversioned_registrar: UncheckedAccount<'info>,
+    #[account(
+        init,
+        seeds = [registrar.key().as_ref(), authority.key().as_ref()],
+        bump = voter_bump,
+        payer = payer,
+        space = Voter::space(VOTER_MAX_CAPACITY),
+    )]
+    pub voter: Box<Account<'info, Voter>>,
+    #[account(
+        init,
+        seeds = [VOTER_WEIGHT_RECORD.as_ref(), registrar.key().as_ref(), authority.key().as_ref()],
+        bump = voter_weight_record_bump,
+        payer = payer,
+        space = 150,
+    )]
+    pub voter_weight_record: Account<'info, VoterWeightRecord>,
+    pub registrar: AccountLoader<'info, Registrar>,
+    // Receives the rent of the unversioned voter.
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
 #[derive(Accounts)]
 pub struct CloseVoter<'info> {
     #[account(mut, has_one = authority, close = sol_destination)]
-    pub voter: AccountLoader<'info, Voter>,
+    pub voter: Box<Account<'info, Voter>>,
     pub authority: Signer<'info>,
     pub sol_destination: UncheckedAccount<'info>,
 }
@@ -2,34 +2,108 @@ use anchor_lang::prelude::*;
 
 #[error]
 pub enum ErrorCode {
-    #[msg("Exchange rate must be greater than zero")]
+    #[msg("Exchange rate numerator and denominator must be greater than zero")]
     InvalidRate,
-    #[msg("")]
+    #[msg("Registrar has no free exchange rate entries")]
     RatesFull,
-    #[msg("")]
+    #[msg("Mint has no exchange rate entry in the registrar")]
     ExchangeRateEntryNotFound,
-    #[msg("")]
+    #[msg("Deposit entry not found")]
     DepositEntryNotFound,
-    #[msg("")]
+    #[msg("All of the voter's deposit entries are in use")]
     DepositEntryFull,
-    #[msg("")]
+    #[msg("Voter still has tokens deposited")]
     VotingTokenNonZero,
-    #[msg("")]
+    #[msg("Deposit id is out of range or the entry isn't in use")]
     InvalidDepositId,
-    #[msg("")]
+    #[msg("Deposit doesn't have enough vested tokens")]
     InsufficientVestedTokens,
-    #[msg("")]
+    #[msg("Amount doesn't fit into a u64")]
     UnableToConvert,
-    #[msg("")]
+    #[msg("Lockup period must be a whole number of days")]
     InvalidLockupPeriod,
-    #[msg("")]
+    #[msg("Lockup must not end before it starts")]
     InvalidEndTs,
-    #[msg("")]
+    #[msg("Lockup days are out of range")]
     InvalidDays,
-    #[msg("")]
+    #[msg("Exchange rate entry is already in use")]
     RateNotZero,
-    #[msg("")]
+    #[msg("Exchange rate index is out of range")]
     InvalidIndex,
-    #[msg("Exchange rate decimals cannot be larger than registrar decimals")]
+    #[msg("Exchange rate and registrar decimals differ too much")]
     InvalidDecimals,
+    #[msg("Max lockup days must be between 1 and 2555 and max boost nonzero")]
+    InvalidLockupConfig,
+    #[msg("Oracle rate bounds must be nonzero and contain the initial rate")]
+    InvalidOracleConfig,
+    #[msg("Invalid price feed account for the exchange rate")]
+    InvalidPriceFeed,
+    #[msg("Oracle exchange rate is stale")]
+    StaleExchangeRate,
+    #[msg("Oracle exchange rate is outside of the configured bounds")]
+    ExchangeRateOutOfBounds,
+    #[msg("Voting mint and voting token accounts must be passed as remaining accounts")]
+    VotingTokenAccountsMissing,
+    #[msg("Voting mint doesn't match the deposit mint")]
+    InvalidVotingMint,
+    #[msg("Voting token account must be owned by the voter and hold the voting mint")]
+    InvalidVotingToken,
+    #[msg("Instruction isn't supported by the registrar's voting token mode")]
+    InvalidVotingTokenMode,
+    #[msg("Account is already at the current version")]
+    AccountUpToDate,
+    #[msg("Exchange vault holds less than the registrar's deposit totals")]
+    VaultBalanceMismatch,
+    #[msg("Mint account was passed more than once")]
+    DuplicateMintAccount,
+    #[msg("Mint accounts must be passed in the order of the registrar's exchange rates")]
+    MintAccountOutOfOrder,
+    #[msg("A mint account is missing for one of the registrar's exchange rates")]
+    MissingMintAccount,
+    #[msg("Governing token mint is neither the realm's community nor council mint")]
+    InvalidRealmGoverningTokenMint,
+    #[msg("Realm authority must sign")]
+    InvalidRealmAuthority,
+    #[msg("Arithmetic overflow or underflow")]
+    ArithmeticOverflow,
+    #[msg("Deposits are paused by the registrar authority")]
+    DepositsPaused,
+    #[msg("Withdrawals are paused by the registrar authority")]
+    WithdrawalsPaused,
+    #[msg("Voter weight updates are paused by the registrar authority")]
+    WeightUpdatesPaused,
+    #[msg("Cliff lockups can't be turned into daily lockups")]
+    InvalidLockupKind,
+    #[msg("Lockup change would re-lock vested tokens")]
+    VestedAmountDecrease,
+    #[msg("Max vote weight must be computed before capping voter weights by it")]
+    MaxVoteWeightNotComputed,
+    #[msg("Voter weight cap fraction can't exceed 10000 basis points")]
+    InvalidVoterWeightCap,
+    #[msg("Remaining accounts must be (voter, voter weight record) pairs")]
+    InvalidVoterWeightRecordPairs,
+    #[msg("Voter doesn't belong to the registrar")]
+    InvalidVoter,
+    #[msg("Voter weight record doesn't belong to the voter")]
+    InvalidVoterWeightRecord,
+    #[msg("Max boost above 100% requires a max vote weight mode that applies it")]
+    BoostExceedsMaxVoteWeight,
+    #[msg("Mint doesn't match the deposit's exchange rate")]
+    InvalidDepositMint,
+    #[msg("Account isn't an unversioned account of the expected type")]
+    InvalidUnversionedAccount,
+    #[msg("Signer isn't the account's authority")]
+    InvalidAuthority,
+    #[msg("Account data isn't a voter")]
+    InvalidVoterData,
+    #[msg("Voter must be upgraded with migrate_voter first")]
+    VoterNotMigrated,
+    #[msg("Voter capacity must grow and can't exceed 32 deposit entries")]
+    InvalidVoterCapacity,
+    #[msg("Realm doesn't belong to the SPL governance program")]
+    InvalidGovernanceProgram,
+    #[msg("Lockup end alignment can't exceed one day")]
+    InvalidLockupEndAlignment,
+    #[msg("Registrar must be upgraded with migrate_registrar first")]
+    RegistrarNotMigrated,
 }
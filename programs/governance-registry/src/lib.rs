@@ -4,16 +4,25 @@ use anchor_lang::prelude::*;
 use anchor_spl::token::{self, Mint};
 use context::*;
 use error::*;
+use oracle::*;
 use spl_governance::addins::voter_weight::VoterWeightAccountType;
 
 mod access_control;
 mod account;
 mod context;
 mod error;
+mod oracle;
+pub mod proposal;
 
 // The program address.
 declare_id!("Fg6PaFpoGXkYsidMpWTK6W2BeZ7FEfcYkg476zPFsLnS");
 
+/// The SPL governance program that registrars' realms must belong to.
+pub mod governance_program {
+    use anchor_lang::declare_id;
+    declare_id!("GovER5Lthms3bLBqWub97yVrMmEogzX7xNjdXpPPCVZw");
+}
+
 /// # Introduction
 ///
 /// The governance registry is an "addin" to the SPL governance program that
@@ -28,7 +37,8 @@ declare_id!("Fg6PaFpoGXkYsidMpWTK6W2BeZ7FEfcYkg476zPFsLnS");
 /// - Add exchange rates for any tokens one wants to deposit. For example,
 ///   if one wants to vote with tokens A and B, where token B has twice the
 ///   voting power of token A, then the exchange rate of B would be 2 and the
-///   exchange rate of A would be 1.
+///   exchange rate of A would be 1. Rates are fractions, so a token worth a
+///   quarter of A would have a rate of 1/4.
 /// - Create a voter account.
 /// - Deposit tokens into this program, with an optional lockup period.
 /// - Vote.
@@ -46,6 +56,11 @@ declare_id!("Fg6PaFpoGXkYsidMpWTK6W2BeZ7FEfcYkg476zPFsLnS");
 /// format, which is then used by SPL governance as the voting power measurement
 /// for a given user.
 ///
+/// The registrar authority can be handed to one of the realm's governance
+/// accounts with `set_registrar_authority`, so that configuration changes
+/// such as adding exchange rates have to pass a proposal vote. The
+/// `proposal` module builds the instructions for such proposals.
+///
 /// # Max Vote Weight
 ///
 /// Given that one can use multiple tokens to vote, the max vote weight needs
@@ -58,23 +73,104 @@ declare_id!("Fg6PaFpoGXkYsidMpWTK6W2BeZ7FEfcYkg476zPFsLnS");
 ///
 /// Note that the above also implies that the `max_vote_weight` must fit into
 /// a u64.
+///
+/// Since voting power is scaled by lockup duration, a registrar can instead
+/// compute the max vote weight as the voting power of the total supply, or of
+/// all tracked deposits, as if it were locked up for the max lockup period.
+/// See `MaxVoteWeightMode`.
+///
+/// # Oracle Exchange Rates
+///
+/// Alternatively, an exchange rate can be pegged to a price feed account. Its
+/// rate is refreshed permissionlessly with `refresh_exchange_rate`, must stay
+/// within the configured bounds, and can't be used once it's older than the
+/// configured staleness limit, except by withdrawals, which use the last
+/// known rate so that a stuck price feed can't lock deposits in. Since such
+/// rates change, the scaled amount of a deposit is recomputed whenever the
+/// deposit is updated and whenever its voting power is measured, and the max
+/// vote weight is only valid for the slot in which it was computed.
+///
+/// # Deposit Totals
+///
+/// The registrar keeps running totals of the amounts deposited, withdrawn and
+/// scaled for each exchange rate, so that aggregate state can be read without
+/// scanning every voter. The amount held according to these totals is checked
+/// against the exchange vault balance on every withdrawal.
+///
+/// # Voting Tokens
+///
+/// By default, depositing mints *frozen* voting tokens to the depositor, one
+/// mint per exchange rate, created with `create_rate_voting_mint`, so that
+/// wallets can display deposited amounts.
+/// With `VotingTokenMode::Scaled`, a single registrar-wide voting mint, created
+/// with `create_voting_mint`, is used instead. It has `rate_decimals` decimals
+/// and is minted in the common currency, so that a wallet shows the total
+/// scaled deposit across all mints. Registrars created with
+/// `VotingTokenMode::Disabled` skip this entirely, so that deposits and
+/// withdrawals only move tokens in and out of the exchange vault. Otherwise,
+/// the voting mint and the depositor's voting token account must be passed as
+/// remaining accounts, in that order, to `create_deposit`, `update_deposit`
+/// and `withdraw`. Deposits create the voting token account if it doesn't
+/// exist yet.
+///
+/// # Pausing
+///
+/// The registrar authority can halt deposits, withdrawals and voter weight
+/// updates independently with `set_pause_flags`, for example when a bug in
+/// the exchange rate or vesting math is found. Lockup resets count as
+/// deposits.
+///
+/// # Weight Aggregation
+///
+/// A voter's weight is the sum of its deposits' voting powers. With
+/// `WeightAggregation::Quadratic`, set with `set_weight_aggregation`, it is
+/// the square root of that sum instead, which limits the influence of large
+/// holders.
+///
+/// The resulting weight can further be capped per voter with
+/// `set_voter_weight_cap`, either absolutely or relative to the max vote
+/// weight. Each voter weight record update also stores the uncapped weight in
+/// the voter's `last_uncapped_weight`, so that capped voters can be found.
+///
+/// # Voter Capacity
+///
+/// New voter accounts have room for `VOTER_INITIAL_CAPACITY` deposit entries,
+/// to keep their rent low. A voter needing more entries is moved into a
+/// larger account with `grow_voter`, up to `VOTER_MAX_CAPACITY` entries.
+///
+/// Voters start at the address with seeds `[registrar, authority]`. A grown
+/// voter moves to `[registrar, authority, &[capacity]]` and its old account
+/// is closed, so a voter exists at only one of these addresses at a time.
+/// Clients find it by fetching the voter accounts whose `authority` and
+/// `registrar`, at offsets 9 and 41 of the account data, match, or by
+/// fetching the addresses of every capacity. The voter weight record keeps
+/// its address.
 #[program]
 pub mod governance_registry {
     use super::*;
 
     /// Creates a new voting registrar. There can only be a single regsitrar
-    /// per governance realm.
+    /// per governing token mint of a governance realm, so a realm can have
+    /// one for its community mint and one for its council mint.
+    ///
+    /// The realm must be owned by the SPL governance program at
+    /// `governance_program::ID`, use the given mint as its community or
+    /// council mint, and its authority must sign.
+    #[access_control(realm_is_valid(&ctx))]
     pub fn create_registrar(
         ctx: Context<CreateRegistrar>,
         rate_decimals: u8,
         registrar_bump: u8,
+        voting_token_mode: VotingTokenMode,
     ) -> Result<()> {
         let registrar = &mut ctx.accounts.registrar.load_init()?;
+        registrar.version = REGISTRAR_VERSION;
         registrar.bump = registrar_bump;
         registrar.realm = ctx.accounts.realm.key();
-        registrar.realm_community_mint = ctx.accounts.realm_community_mint.key();
+        registrar.realm_governing_token_mint = ctx.accounts.realm_governing_token_mint.key();
         registrar.authority = ctx.accounts.authority.key();
         registrar.rate_decimals = rate_decimals;
+        registrar.voting_token_mode = voting_token_mode;
 
         Ok(())
     }
@@ -83,22 +179,208 @@ pub mod governance_registry {
     /// deposit the mint in exchange for vTokens. There can only be a single
     /// exchange rate per mint.
     ///
+    /// Registrars with `VotingTokenMode::PerMint` also need the rate's voting
+    /// mint, created with `create_rate_voting_mint`, before any deposit.
+    ///
     /// WARNING: This can be freely called when any of the rates are empty.
     ///          This should be called immediately upon creation of a Registrar.
-    #[access_control(rate_is_empty(&ctx, idx))]
+    #[access_control(registrar_is_migrated(&ctx.accounts.registrar), rate_is_empty(&ctx, idx))]
     pub fn create_exchange_rate(
         ctx: Context<CreateExchangeRate>,
         idx: u16,
         er: ExchangeRateEntry,
     ) -> Result<()> {
-        require!(er.rate > 0, InvalidRate);
+        require!(er.rate > 0 && er.rate_denominator > 0, InvalidRate);
+        require!(er.is_valid_lockup_config(), InvalidLockupConfig);
+        require!(er.is_valid_oracle_config(), InvalidOracleConfig);
         let registrar = &mut ctx.accounts.registrar.load_mut()?;
+        require!(registrar.is_valid_boost(&er), BoostExceedsMaxVoteWeight);
         registrar.rates[idx as usize] = er;
+        // Oracle-backed rates must be refreshed before first use.
+        registrar.rates[idx as usize].last_update_slot = 0;
+        Ok(())
+    }
+
+    /// Refreshes an oracle-backed exchange rate from its price feed. Anyone
+    /// can call this, e.g. in the same transaction as a deposit or vote.
+    #[access_control(registrar_is_migrated(&ctx.accounts.registrar))]
+    pub fn refresh_exchange_rate(ctx: Context<RefreshExchangeRate>, idx: u16) -> Result<()> {
+        let registrar = &mut ctx.accounts.registrar.load_mut()?;
+        require!((idx as usize) < registrar.rates.len(), InvalidIndex);
+
+        let er = &mut registrar.rates[idx as usize];
+        require!(er.is_oracle(), InvalidPriceFeed);
+        require!(er.oracle == ctx.accounts.price_feed.key(), InvalidPriceFeed);
+
+        let feed = PriceFeed::load(&ctx.accounts.price_feed.try_borrow_data()?)?;
+        er.update_from_feed(&feed, Clock::get()?.slot)
+    }
+
+    /// Sets the policy deciding which deposits count towards the voter weight
+    /// for the given action. For example, proposal creation can be limited to
+    /// deposits locked up for a minimum number of days.
+    #[access_control(registrar_is_migrated(&ctx.accounts.registrar))]
+    pub fn set_weight_policy(
+        ctx: Context<UpdateRegistrar>,
+        action: WeightAction,
+        policy: WeightPolicy,
+    ) -> Result<()> {
+        let registrar = &mut ctx.accounts.registrar.load_mut()?;
+        registrar.weight_policies[action as usize] = policy;
+        Ok(())
+    }
+
+    /// Sets how the max vote weight is computed.
+    ///
+    /// `MaxVoteWeightMode::Supply` can't be used with rates boosted above
+    /// 100%, since a voter could then outweigh the max vote weight.
+    #[access_control(registrar_is_migrated(&ctx.accounts.registrar))]
+    pub fn set_max_vote_weight_mode(
+        ctx: Context<UpdateRegistrar>,
+        mode: MaxVoteWeightMode,
+    ) -> Result<()> {
+        let registrar = &mut ctx.accounts.registrar.load_mut()?;
+        registrar.max_vote_weight_mode = mode;
+        require!(
+            registrar
+                .rates
+                .iter()
+                .all(|er| registrar.is_valid_boost(er)),
+            BoostExceedsMaxVoteWeight
+        );
+        Ok(())
+    }
+
+    /// Sets which parts of the program are paused. Only the registrar
+    /// authority can call this.
+    #[access_control(registrar_is_migrated(&ctx.accounts.registrar))]
+    pub fn set_pause_flags(ctx: Context<UpdateRegistrar>, flags: PauseFlags) -> Result<()> {
+        ctx.accounts.registrar.load_mut()?.pause_flags = flags;
+        Ok(())
+    }
+
+    /// Sets the period, in seconds since the unix epoch, that new lockups are
+    /// aligned to end on, e.g. 86_400 for UTC midnight. Zero disables the
+    /// alignment. The period can't exceed `MAX_LOCKUP_END_ALIGNMENT`.
+    #[access_control(registrar_is_migrated(&ctx.accounts.registrar))]
+    pub fn set_lockup_end_alignment(ctx: Context<UpdateRegistrar>, period: u32) -> Result<()> {
+        require!(
+            period <= MAX_LOCKUP_END_ALIGNMENT,
+            InvalidLockupEndAlignment
+        );
+        ctx.accounts.registrar.load_mut()?.lockup_end_alignment = period;
+        Ok(())
+    }
+
+    /// Sets how deposit voting powers are combined into a voter weight.
+    ///
+    /// Note that the max vote weight isn't adjusted for quadratic
+    /// aggregation, since the sum of the voters' square roots depends on how
+    /// deposits are spread across voters.
+    #[access_control(registrar_is_migrated(&ctx.accounts.registrar))]
+    pub fn set_weight_aggregation(
+        ctx: Context<UpdateRegistrar>,
+        aggregation: WeightAggregation,
+    ) -> Result<()> {
+        ctx.accounts.registrar.load_mut()?.weight_aggregation = aggregation;
+        Ok(())
+    }
+
+    /// Sets the limit on any single voter's weight. A limit relative to the
+    /// max vote weight uses the result of the last `update_max_vote_weight`.
+    #[access_control(registrar_is_migrated(&ctx.accounts.registrar))]
+    pub fn set_voter_weight_cap(ctx: Context<UpdateRegistrar>, cap: VoterWeightCap) -> Result<()> {
+        require!(
+            cap.max_vote_weight_bps <= BPS_DENOMINATOR,
+            InvalidVoterWeightCap
+        );
+        ctx.accounts.registrar.load_mut()?.voter_weight_cap = cap;
+        Ok(())
+    }
+
+    /// Transfers control over the registrar's configuration, for example to a
+    /// governance account of the realm.
+    #[access_control(registrar_is_migrated(&ctx.accounts.registrar))]
+    pub fn set_registrar_authority(
+        ctx: Context<UpdateRegistrar>,
+        new_authority: Pubkey,
+    ) -> Result<()> {
+        ctx.accounts.registrar.load_mut()?.authority = new_authority;
+        Ok(())
+    }
+
+    /// Upgrades the registrar account to the current layout version. Until
+    /// then, instructions using the registrar fail with
+    /// `RegistrarNotMigrated`.
+    pub fn migrate_registrar(ctx: Context<UpdateRegistrar>) -> Result<()> {
+        ctx.accounts.registrar.load_mut()?.migrate()
+    }
+
+    /// Copies a registrar created before accounts were versioned into a new
+    /// account at its current address, see `Registrar::from_unversioned`.
+    /// Such registrars were derived from the realm alone and bound to its
+    /// community mint. Only their authority can call this.
+    ///
+    /// The exchange vaults are moved over with
+    /// `migrate_unversioned_exchange_vault`, and voters with
+    /// `migrate_unversioned_voter`. Voting tokens issued by the unversioned
+    /// registrar can't be moved, so the migrated registrar doesn't issue any.
+    pub fn migrate_unversioned_registrar(
+        ctx: Context<MigrateUnversionedRegistrar>,
+        registrar_bump: u8,
+    ) -> Result<()> {
+        let unversioned =
+            Registrar::from_unversioned(&ctx.accounts.unversioned_registrar.try_borrow_data()?)?;
+        require!(
+            unversioned.authority == ctx.accounts.authority.key(),
+            InvalidAuthority
+        );
+        require!(
+            unversioned.realm == ctx.accounts.realm.key()
+                && unversioned.realm_governing_token_mint
+                    == ctx.accounts.realm_governing_token_mint.key(),
+            InvalidUnversionedAccount
+        );
+
+        let registrar = &mut ctx.accounts.registrar.load_init()?;
+        **registrar = unversioned;
+        registrar.bump = registrar_bump;
+        registrar.voting_token_mode = VotingTokenMode::Disabled;
         Ok(())
     }
 
+    /// Moves the tokens in an exchange vault of a registrar created before
+    /// accounts were versioned into the vault of its migrated registrar.
+    /// Anyone can call this.
+    #[access_control(registrar_is_migrated(&ctx.accounts.registrar))]
+    pub fn migrate_unversioned_exchange_vault(
+        ctx: Context<MigrateUnversionedExchangeVault>,
+    ) -> Result<()> {
+        let unversioned =
+            Registrar::from_unversioned(&ctx.accounts.unversioned_registrar.try_borrow_data()?)?;
+        {
+            let registrar = ctx.accounts.registrar.load()?;
+            require!(
+                registrar.realm == unversioned.realm
+                    && registrar.realm_governing_token_mint
+                        == unversioned.realm_governing_token_mint,
+                InvalidUnversionedAccount
+            );
+        }
+
+        // Unversioned registrars were derived from the realm alone.
+        let amount = ctx.accounts.unversioned_exchange_vault.amount;
+        token::transfer(
+            ctx.accounts
+                .transfer_ctx()
+                .with_signer(&[&[unversioned.realm.as_ref(), &[unversioned.bump]]]),
+            amount,
+        )
+    }
+
     /// Creates a new voter account. There can only be a single voter per
     /// user wallet.
+    #[access_control(registrar_is_migrated(&ctx.accounts.registrar))]
     pub fn create_voter(
         ctx: Context<CreateVoter>,
         voter_bump: u8,
@@ -106,10 +388,11 @@ pub mod governance_registry {
     ) -> Result<()> {
         // Load accounts.
         let registrar = &ctx.accounts.registrar.load()?;
-        let voter = &mut ctx.accounts.voter.load_init()?;
+        let voter = &mut ctx.accounts.voter;
         let voter_weight_record = &mut ctx.accounts.voter_weight_record;
 
-        // Init the voter.
+        // Init the voter. Its capacity is set from the account size.
+        voter.version = VOTER_VERSION;
         voter.voter_bump = voter_bump;
         voter.voter_weight_record_bump = voter_weight_record_bump;
         voter.authority = ctx.accounts.authority.key();
@@ -118,41 +401,72 @@ pub mod governance_registry {
         // Init the voter weight record.
         voter_weight_record.account_type = VoterWeightAccountType::VoterWeightRecord;
         voter_weight_record.realm = registrar.realm;
-        voter_weight_record.governing_token_mint = registrar.realm_community_mint;
+        voter_weight_record.governing_token_mint = registrar.realm_governing_token_mint;
         voter_weight_record.governing_token_owner = ctx.accounts.authority.key();
 
         Ok(())
     }
 
     /// Creates a new deposit entry and updates it by transferring in tokens.
-    pub fn create_deposit(
-        ctx: Context<CreateDeposit>,
+    ///
+    /// The lockup lasts `duration` units of `granularity`: whole days, or
+    /// seconds for lockups ending at an arbitrary timestamp. If the registrar
+    /// has a `lockup_end_alignment`, the lockup is shifted forward to end on
+    /// the next aligned timestamp, so that unlocks happen together. The
+    /// shifted end must still be within the mint's max lockup.
+    #[access_control(registrar_is_migrated(&ctx.accounts.deposit.registrar))]
+    pub fn create_deposit<'info>(
+        ctx: Context<'_, '_, '_, 'info, CreateDeposit<'info>>,
         kind: LockupKind,
+        granularity: LockupGranularity,
         amount: u64,
-        days: i32,
+        duration: i64,
     ) -> Result<()> {
         // Creates the new deposit.
         let deposit_id = {
             // Load accounts.
             let registrar = &ctx.accounts.deposit.registrar.load()?;
-            let voter = &mut ctx.accounts.deposit.voter.load_mut()?;
-
-            // Set the lockup start timestamp.
-            let start_ts = Clock::get()?.unix_timestamp;
+            let voter = &mut ctx.accounts.deposit.voter;
 
             // Get the exchange rate entry associated with this deposit.
             let er_idx = registrar
                 .rates
                 .iter()
                 .position(|r| r.mint == ctx.accounts.deposit.deposit_mint.key())
-                .ok_or(ErrorCode::ExchangeRateEntryNotFound)?;
+                .ok_or_else(|| {
+                    msg!(
+                        "no exchange rate for mint {}",
+                        ctx.accounts.deposit.deposit_mint.key()
+                    );
+                    ErrorCode::ExchangeRateEntryNotFound
+                })?;
+            let max_lockup_secs = registrar.rates[er_idx].max_lockup_days as i64 * SECS_PER_DAY;
+            let lockup_secs = duration.saturating_mul(granularity.unit_secs());
+            if !(0..=max_lockup_secs).contains(&lockup_secs) {
+                msg!(
+                    "lockup of {} seconds not in [0, {}]",
+                    lockup_secs,
+                    max_lockup_secs
+                );
+                return Err(ErrorCode::InvalidDays.into());
+            }
 
             // Get and set up the first free deposit entry.
-            let free_entry_idx = voter
-                .deposits
-                .iter()
-                .position(|d_entry| !d_entry.is_used)
-                .ok_or(ErrorCode::DepositEntryFull)?;
+            let free_entry_idx = voter.free_deposit_idx().ok_or_else(|| {
+                msg!(
+                    "voter has no free deposit entries, capacity {}; add more with grow_voter",
+                    voter.capacity
+                );
+                ErrorCode::DepositEntryFull
+            })?;
+
+            // Set the lockup start and end timestamps.
+            let (start_ts, end_ts) = registrar.lockup_bounds(
+                Clock::get()?.unix_timestamp,
+                lockup_secs,
+                max_lockup_secs,
+            )?;
+
             let d_entry = &mut voter.deposits[free_entry_idx];
             d_entry.is_used = true;
             d_entry.rate_idx = free_entry_idx as u8;
@@ -161,161 +475,297 @@ pub mod governance_registry {
             d_entry.lockup = Lockup {
                 kind,
                 start_ts,
-                end_ts: start_ts
-                    .checked_add(i64::from(days).checked_mul(SECS_PER_DAY).unwrap())
-                    .unwrap(),
-                padding: [0u8; 16],
+                end_ts,
+                granularity,
+                padding: [0u8; 15],
             };
 
             free_entry_idx as u8
         };
 
         // Updates the entry by transferring in tokens.
-        let update_ctx = Context::new(ctx.program_id, &mut ctx.accounts.deposit, &[]);
+        let update_ctx = Context::new(
+            ctx.program_id,
+            &mut ctx.accounts.deposit,
+            ctx.remaining_accounts,
+        );
         update_deposit(update_ctx, deposit_id, amount)?;
 
         Ok(())
     }
 
     /// Updates a deposit entry by depositing tokens into the registrar in
-    /// exchange for *frozen* voting tokens. These tokens are not used for
-    /// anything other than displaying the amount in wallets.
-    pub fn update_deposit(ctx: Context<UpdateDeposit>, id: u8, amount: u64) -> Result<()> {
-        let registrar = &ctx.accounts.registrar.load()?;
-        let voter = &mut ctx.accounts.voter.load_mut()?;
-
-        // Calculate the amount of voting tokens to mint at the specified
-        // exchange rate.
-        let amount_scaled = {
-            // Get the exchange rate entry associated with this deposit.
-            let er_idx = registrar
-                .rates
-                .iter()
-                .position(|r| r.mint == ctx.accounts.deposit_mint.key())
-                .ok_or(ErrorCode::ExchangeRateEntryNotFound)?;
-            let er_entry = registrar.rates[er_idx];
-            registrar.convert(&er_entry, amount)?
-        };
+    /// exchange for *frozen* voting tokens, unless the registrar has voting
+    /// tokens disabled. These tokens are not used for anything other than
+    /// displaying the amount in wallets.
+    #[access_control(registrar_is_migrated(&ctx.accounts.registrar))]
+    pub fn update_deposit<'info>(
+        ctx: Context<'_, '_, '_, 'info, UpdateDeposit<'info>>,
+        id: u8,
+        amount: u64,
+    ) -> Result<()> {
+        let mut registrar = ctx.accounts.registrar.load_mut()?;
+        let voter = &mut ctx.accounts.voter;
+        require!(!registrar.pause_flags.deposits, DepositsPaused);
+
+        let d_entry = voter.deposit_mut(id)?;
+
+        // Get the exchange rate entry associated with this deposit.
+        let er_idx = registrar.deposit_rate_idx(d_entry, &ctx.accounts.deposit_mint.key())?;
+        let er_entry = registrar.rates[er_idx];
+        er_entry.require_fresh(Clock::get()?.slot)?;
+
+        let d_before = *d_entry;
+        d_entry.amount_deposited = d_entry
+            .amount_deposited
+            .checked_add(amount)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        // Rescale the entire deposit, since the rate may have changed.
+        d_entry.amount_scaled = registrar.convert(&er_entry, d_entry.amount_deposited)?;
+        registrar.deposit_totals[er_idx].record_deposit(
+            amount,
+            d_before.amount_scaled,
+            d_entry.amount_scaled,
+        )?;
 
-        require!(voter.deposits.len() > id as usize, InvalidDepositId);
-        let d_entry = &mut voter.deposits[id as usize];
-        d_entry.amount_deposited += amount;
-        d_entry.amount_scaled += amount_scaled;
+        let voting_token_mode = registrar.voting_token_mode;
+        let voting_tokens_before = voting_token_mode.voting_tokens(&d_before);
+        let voting_tokens_after = voting_token_mode.voting_tokens(d_entry);
+        let (realm, mint, bump) = (
+            registrar.realm,
+            registrar.realm_governing_token_mint,
+            registrar.bump,
+        );
+        // Release the registrar, since it signs the CPIs below.
+        drop(registrar);
 
         // Deposit tokens into the registrar.
         token::transfer(ctx.accounts.transfer_ctx(), amount)?;
 
-        // Thaw the account if it's frozen, so that we can mint.
-        if ctx.accounts.voting_token.is_frozen() {
-            token::thaw_account(
-                ctx.accounts
-                    .thaw_ctx()
-                    .with_signer(&[&[registrar.realm.as_ref(), &[registrar.bump]]]),
-            )?;
+        if voting_token_mode == VotingTokenMode::Disabled {
+            return Ok(());
         }
 
-        // Mint vote tokens to the depositor.
-        token::mint_to(
+        // Mint vote tokens to the depositor, creating their voting token
+        // account on their first deposit.
+        if let [voting_mint, voting_token, ..] = ctx.remaining_accounts {
             ctx.accounts
-                .mint_to_ctx()
-                .with_signer(&[&[registrar.realm.as_ref(), &[registrar.bump]]]),
-            amount,
-        )?;
-
-        // Freeze the vote tokens; they are just used for UIs + accounting.
-        token::freeze_account(
-            ctx.accounts
-                .freeze_ctx()
-                .with_signer(&[&[registrar.realm.as_ref(), &[registrar.bump]]]),
-        )?;
-
-        Ok(())
+                .create_voting_token(voting_mint, voting_token)?;
+        }
+        VotingTokenAccounts::load(
+            ctx.program_id,
+            voting_token_mode,
+            &ctx.accounts.registrar.key(),
+            &ctx.accounts.deposit_mint.key(),
+            &ctx.accounts.authority.key(),
+            ctx.remaining_accounts,
+        )?
+        .update_balance(
+            &ctx.accounts.token_program,
+            &ctx.accounts.registrar,
+            &ctx.accounts.authority,
+            &[&[realm.as_ref(), mint.as_ref(), &[bump]]],
+            voting_tokens_before,
+            voting_tokens_after,
+        )
     }
 
     /// Withdraws tokens from a deposit entry, if they are unlocked according
     /// to a vesting schedule.
     ///
     /// `amount` is in units of the native currency being withdrawn.
-    pub fn withdraw(ctx: Context<Withdraw>, deposit_id: u8, amount: u64) -> Result<()> {
+    #[access_control(registrar_is_migrated(&ctx.accounts.registrar))]
+    pub fn withdraw<'info>(
+        ctx: Context<'_, '_, '_, 'info, Withdraw<'info>>,
+        deposit_id: u8,
+        amount: u64,
+    ) -> Result<()> {
         // Load the accounts.
-        let registrar = &ctx.accounts.registrar.load()?;
-        let voter = &mut ctx.accounts.voter.load_mut()?;
-        require!(voter.deposits.len() > deposit_id.into(), InvalidDepositId);
+        let mut registrar = ctx.accounts.registrar.load_mut()?;
+        let voter = &mut ctx.accounts.voter;
+        require!(!registrar.pause_flags.withdrawals, WithdrawalsPaused);
 
         // Get the deposit being withdrawn from.
-        let deposit_entry = &mut voter.deposits[deposit_id as usize];
-        require!(deposit_entry.is_used, InvalidDepositId);
-        require!(deposit_entry.vested()? >= amount, InsufficientVestedTokens);
+        let deposit_entry = voter.used_deposit_mut(deposit_id)?;
+        let vested = deposit_entry.vested()?;
+        let amount_left = deposit_entry.amount_left()?;
+        if vested < amount || amount_left < amount {
+            msg!(
+                "deposit {} of mint {}: requested {}, vested {}, left {}",
+                deposit_id,
+                ctx.accounts.withdraw_mint.key(),
+                amount,
+                vested,
+                amount_left
+            );
+            return Err(ErrorCode::InsufficientVestedTokens.into());
+        }
+
+        // Get the exchange rate for the token being withdrawn.
+        let er_idx =
+            registrar.deposit_rate_idx(deposit_entry, &ctx.accounts.withdraw_mint.key())?;
+        // The last known rate is used even if it's stale, so that a stuck
+        // oracle can't lock deposits in. It only rescales what's left.
+        let er_entry = registrar.rates[er_idx];
+
+        // The vault must hold at least what the registrar's deposits account
+        // for.
         require!(
-            deposit_entry.amount_left() >= amount,
-            InsufficientVestedTokens
+            ctx.accounts.exchange_vault.amount >= registrar.deposit_totals[er_idx].amount_held(),
+            VaultBalanceMismatch
         );
 
-        // Scale the amount being withdrawn by the exchange rate.
-        let amount_scaled = {
-            // Get the exchange rate for the token being withdrawn.
-            let er_idx = registrar
-                .rates
-                .iter()
-                .position(|r| r.mint == ctx.accounts.withdraw_mint.key())
-                .ok_or(ErrorCode::ExchangeRateEntryNotFound)?;
-            let er_entry = registrar.rates[er_idx];
-            registrar.convert(&er_entry, amount)?
-        };
-
-        // Update deposit book keeping.
-        deposit_entry.amount_scaled -= amount_scaled;
+        // Update deposit book keeping, rescaling what's left by the exchange
+        // rate.
+        let d_before = *deposit_entry;
         deposit_entry.amount_deposited -= amount;
-        deposit_entry.amount_withdrawn += amount;
+        deposit_entry.amount_withdrawn = deposit_entry
+            .amount_withdrawn
+            .checked_add(amount)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        deposit_entry.amount_scaled =
+            registrar.convert(&er_entry, deposit_entry.amount_deposited)?;
+        registrar.deposit_totals[er_idx].record_withdrawal(
+            amount,
+            d_before.amount_scaled,
+            deposit_entry.amount_scaled,
+        )?;
+
+        let voting_token_mode = registrar.voting_token_mode;
+        let voting_tokens_before = voting_token_mode.voting_tokens(&d_before);
+        let voting_tokens_after = voting_token_mode.voting_tokens(deposit_entry);
+        let (realm, mint, bump) = (
+            registrar.realm,
+            registrar.realm_governing_token_mint,
+            registrar.bump,
+        );
+        // Release the registrar, since it signs the CPIs below.
+        drop(registrar);
 
         // Transfer the tokens to withdraw.
         token::transfer(
             ctx.accounts
                 .transfer_ctx()
-                .with_signer(&[&[registrar.realm.as_ref(), &[registrar.bump]]]),
+                .with_signer(&[&[realm.as_ref(), mint.as_ref(), &[bump]]]),
             amount,
         )?;
 
-        // Unfreeze the voting token.
-        token::thaw_account(
-            ctx.accounts
-                .thaw_ctx()
-                .with_signer(&[&[registrar.realm.as_ref(), &[registrar.bump]]]),
-        )?;
+        if voting_token_mode == VotingTokenMode::Disabled {
+            return Ok(());
+        }
 
         // Burn the voting tokens.
-        token::burn(ctx.accounts.burn_ctx(), amount)?;
+        VotingTokenAccounts::load(
+            ctx.program_id,
+            voting_token_mode,
+            &ctx.accounts.registrar.key(),
+            &ctx.accounts.withdraw_mint.key(),
+            &ctx.accounts.authority.key(),
+            ctx.remaining_accounts,
+        )?
+        .update_balance(
+            &ctx.accounts.token_program,
+            &ctx.accounts.registrar,
+            &ctx.accounts.authority,
+            &[&[realm.as_ref(), mint.as_ref(), &[bump]]],
+            voting_tokens_before,
+            voting_tokens_after,
+        )
+    }
 
-        // Re-freeze the vote token.
-        token::freeze_account(
-            ctx.accounts
-                .freeze_ctx()
-                .with_signer(&[&[registrar.realm.as_ref(), &[registrar.bump]]]),
-        )?;
+    /// Creates the voting mint of an exchange rate, used by registrars with
+    /// `VotingTokenMode::PerMint`. Anyone can call this.
+    #[access_control(registrar_is_migrated(&ctx.accounts.registrar))]
+    pub fn create_rate_voting_mint(ctx: Context<CreateRateVotingMint>) -> Result<()> {
+        let registrar = ctx.accounts.registrar.load()?;
+        require!(
+            registrar.voting_token_mode == VotingTokenMode::PerMint,
+            InvalidVotingTokenMode
+        );
+        let deposit_mint = ctx.accounts.deposit_mint.key();
+        if !registrar
+            .rates
+            .iter()
+            .any(|er| er.rate > 0 && er.mint == deposit_mint)
+        {
+            msg!("no exchange rate for mint {}", deposit_mint);
+            return Err(ErrorCode::ExchangeRateEntryNotFound.into());
+        }
+        Ok(())
+    }
 
+    /// Creates the registrar-wide voting mint used by registrars with
+    /// `VotingTokenMode::Scaled`. Anyone can call this.
+    #[access_control(registrar_is_migrated(&ctx.accounts.registrar))]
+    pub fn create_voting_mint(ctx: Context<CreateVotingMint>) -> Result<()> {
+        let registrar = ctx.accounts.registrar.load()?;
+        require!(
+            registrar.voting_token_mode == VotingTokenMode::Scaled,
+            InvalidVotingTokenMode
+        );
         Ok(())
     }
 
     /// Resets a lockup to start at the current slot timestamp and to last for
     /// `days`, which must be longer than the number of days left on the lockup.
+    ///
+    /// Resets that would re-lock vested tokens are rejected, see
+    /// `DepositEntry::reset_lockup`.
+    #[access_control(registrar_is_migrated(&ctx.accounts.registrar))]
     pub fn reset_lockup(ctx: Context<UpdateSchedule>, deposit_id: u8, days: i64) -> Result<()> {
-        let voter = &mut ctx.accounts.voter.load_mut()?;
-        require!(voter.deposits.len() > deposit_id as usize, InvalidDepositId);
-
-        let d = &mut voter.deposits[deposit_id as usize];
-        require!(d.is_used, InvalidDepositId);
+        let registrar = &ctx.accounts.registrar.load()?;
+        let voter = &mut ctx.accounts.voter;
+        require!(!registrar.pause_flags.deposits, DepositsPaused);
+        let d = voter.used_deposit_mut(deposit_id)?;
 
-        // The lockup period can only be increased.
+        // The lockup period can't exceed the maximum for the deposit's mint
+        // and can only be increased.
+        let er = registrar.rates[d.rate_idx as usize];
         let curr_ts = Clock::get()?.unix_timestamp;
-        require!(days as u64 > d.lockup.days_left(curr_ts)?, InvalidDays);
+        let days_left = d.lockup.days_left(curr_ts)?;
+        if days < 0 || days as u64 > er.max_lockup_days || days as u64 <= days_left {
+            msg!(
+                "lockup days {} not in ({}, {}]",
+                days,
+                days_left,
+                er.max_lockup_days
+            );
+            return Err(ErrorCode::InvalidDays.into());
+        }
 
-        let start_ts = Clock::get()?.unix_timestamp;
-        let end_ts = start_ts
-            .checked_add(days.checked_mul(SECS_PER_DAY).unwrap())
-            .unwrap();
+        d.reset_lockup(days as u64, curr_ts)
+    }
 
-        d.lockup.start_ts = start_ts;
-        d.lockup.end_ts = end_ts;
+    /// Moves the end of a lockup `days` further out, optionally turning a
+    /// `Daily` lockup into a `Cliff` one. Unlike `reset_lockup`, this never
+    /// re-locks vested tokens, including those of partially vested `Daily`
+    /// lockups, see `DepositEntry::extend_lockup`.
+    #[access_control(registrar_is_migrated(&ctx.accounts.registrar))]
+    pub fn extend_lockup(
+        ctx: Context<UpdateSchedule>,
+        deposit_id: u8,
+        kind: LockupKind,
+        days: u64,
+    ) -> Result<()> {
+        let registrar = &ctx.accounts.registrar.load()?;
+        let voter = &mut ctx.accounts.voter;
+        require!(!registrar.pause_flags.deposits, DepositsPaused);
+        let d = voter.used_deposit_mut(deposit_id)?;
+
+        let curr_ts = Clock::get()?.unix_timestamp;
+        d.extend_lockup(days, kind, curr_ts)?;
+
+        // The extended lockup can't exceed the maximum for the deposit's mint.
+        let er = registrar.rates[d.rate_idx as usize];
+        let days_left = d.lockup.days_left(curr_ts)?;
+        if days_left > er.max_lockup_days {
+            msg!(
+                "lockup days left {} exceed {}",
+                days_left,
+                er.max_lockup_days
+            );
+            return Err(ErrorCode::InvalidDays.into());
+        }
 
         Ok(())
     }
@@ -326,60 +776,243 @@ pub mod governance_registry {
     ///
     /// This "revise" instruction should be called in the same transaction,
     /// immediately before voting.
-    pub fn update_voter_weight_record(ctx: Context<UpdateVoterWeightRecord>) -> Result<()> {
-        let voter = ctx.accounts.voter.load()?;
-        let record = &mut ctx.accounts.voter_weight_record;
-        record.voter_weight = voter.weight()?;
-        record.voter_weight_expiry = Some(Clock::get()?.slot);
+    ///
+    /// The record is scoped to `weight_action` and, if given, to
+    /// `weight_action_target` (e.g. the proposal being voted on), so that it
+    /// can't be reused for a different action or target in the same slot.
+    ///
+    /// Anyone can call this, e.g. relayers bundling it with a vote, since the
+    /// weight only depends on public state. The record must still be the
+    /// voter's own.
+    #[access_control(registrar_is_migrated(&ctx.accounts.registrar))]
+    pub fn update_voter_weight_record(
+        ctx: Context<UpdateVoterWeightRecord>,
+        weight_action: WeightAction,
+        weight_action_target: Option<Pubkey>,
+    ) -> Result<()> {
+        let registrar = ctx.accounts.registrar.load()?;
+        require!(!registrar.pause_flags.weight_updates, WeightUpdatesPaused);
+        ctx.accounts.voter.update_weight_record(
+            &registrar,
+            &mut ctx.accounts.voter_weight_record,
+            weight_action,
+            weight_action_target,
+        )
+    }
+
+    /// Refreshes the voter weight records of many voters at once, e.g. for
+    /// keepers before a vote closes. Anyone can call this, since the weights
+    /// only depend on public state.
+    ///
+    /// Remaining accounts are `(Voter, VoterWeightRecord)` pairs of the
+    /// registrar's voters, all writable.
+    #[access_control(registrar_is_migrated(&ctx.accounts.registrar))]
+    pub fn update_voter_weight_records<'info>(
+        ctx: Context<'_, '_, '_, 'info, UpdateVoterWeightRecords<'info>>,
+        weight_action: WeightAction,
+        weight_action_target: Option<Pubkey>,
+    ) -> Result<()> {
+        let registrar_key = ctx.accounts.registrar.key();
+        let registrar = ctx.accounts.registrar.load()?;
+        require!(!registrar.pause_flags.weight_updates, WeightUpdatesPaused);
+        require!(
+            ctx.remaining_accounts.len() % 2 == 0,
+            InvalidVoterWeightRecordPairs
+        );
+
+        for pair in ctx.remaining_accounts.chunks(2) {
+            let mut voter = Box::new(Account::<Voter>::try_from(&pair[0])?);
+            require!(voter.registrar == registrar_key, InvalidVoter);
+
+            let mut record = Account::<VoterWeightRecord>::try_from(&pair[1])?;
+            let record_address = Pubkey::create_program_address(
+                &[
+                    VOTER_WEIGHT_RECORD.as_ref(),
+                    registrar_key.as_ref(),
+                    voter.authority.as_ref(),
+                    &[voter.voter_weight_record_bump],
+                ],
+                ctx.program_id,
+            )
+            .map_err(|_| ErrorCode::InvalidVoterWeightRecord)?;
+            require!(record.key() == record_address, InvalidVoterWeightRecord);
+            require!(
+                record.realm == registrar.realm && record.governing_token_owner == voter.authority,
+                InvalidVoterWeightRecord
+            );
+
+            voter.update_weight_record(
+                &registrar,
+                &mut record,
+                weight_action,
+                weight_action_target,
+            )?;
+            voter.exit(ctx.program_id)?;
+            record.exit(ctx.program_id)?;
+        }
 
         Ok(())
     }
 
     /// Calculates the max vote weight for the registry. This is a function
     /// of the total supply of all exchange rate mints, converted into a
-    /// common currency with a common number of decimals.
+    /// common currency with a common number of decimals, or of the
+    /// registrar's deposit totals, depending on its `MaxVoteWeightMode`.
+    /// Mint accounts aren't needed for the latter.
     ///
     /// Note that this method is only safe to use if the cumulative supply for
     /// all tokens fits into a u64 *after* converting into common decimals, as
     /// defined by the registrar's `rate_decimal` field.
+    #[access_control(registrar_is_migrated(&ctx.accounts.registrar))]
     pub fn update_max_vote_weight<'info>(
         ctx: Context<'_, '_, '_, 'info, UpdateMaxVoteWeight<'info>>,
     ) -> Result<()> {
-        let registrar = ctx.accounts.registrar.load()?;
-        let _max_vote_weight = {
-            let total: Result<u64> = ctx
+        let mut registrar = ctx.accounts.registrar.load_mut()?;
+        let curr_slot = Clock::get()?.slot;
+        let max_vote_weight = if registrar.max_vote_weight_mode == MaxVoteWeightMode::DepositTotals
+        {
+            let total: Result<u64> = registrar
+                .rates
+                .iter()
+                .zip(registrar.deposit_totals.iter())
+                .filter(|(er, _)| er.rate > 0)
+                .try_fold(0u64, |sum, (er, totals)| {
+                    er.require_fresh(curr_slot)?;
+                    let amount = registrar.max_voting_power(er, totals.amount_held())?;
+                    let total = sum
+                        .checked_add(amount)
+                        .ok_or(ErrorCode::ArithmeticOverflow)?;
+                    Ok(total)
+                });
+            total?
+        } else {
+            let mints = ctx
                 .remaining_accounts
                 .iter()
                 .map(|acc| Account::<Mint>::try_from(acc))
-                .collect::<std::result::Result<Vec<Account<Mint>>, ProgramError>>()?
+                .collect::<std::result::Result<Vec<Account<Mint>>, ProgramError>>()?;
+            registrar.require_rate_mints(&mints.iter().map(|m| m.key()).collect::<Vec<_>>())?;
+
+            let total: Result<u64> = mints
                 .iter()
-                .try_fold(0u64, |sum, m| {
-                    let er_idx = registrar
-                        .rates
-                        .iter()
-                        .position(|r| r.mint == m.key())
-                        .ok_or(ErrorCode::ExchangeRateEntryNotFound)?;
-                    let er_entry = registrar.rates[er_idx];
-                    let amount = registrar.convert(&er_entry, m.supply)?;
-                    let total = sum.checked_add(amount).unwrap();
+                .zip(registrar.rates.iter().filter(|er| er.rate > 0))
+                .try_fold(0u64, |sum, (m, er_entry)| {
+                    er_entry.require_fresh(curr_slot)?;
+                    let amount = registrar.max_voting_power(er_entry, m.supply)?;
+                    let total = sum
+                        .checked_add(amount)
+                        .ok_or(ErrorCode::ArithmeticOverflow)?;
                     Ok(total)
                 });
             total?
         };
+        // Kept for capping voter weights by a fraction of it.
+        registrar.max_vote_weight = max_vote_weight;
         // TODO: SPL governance has not yet implemented this feature.
         //       When it has, probably need to write the result into an account,
         //       similar to VoterWeightRecord.
         Ok(())
     }
 
+    /// Upgrades a voter account to the current layout version. Anyone can call
+    /// this, since the upgrade doesn't depend on any input.
+    ///
+    /// Voters from before version 3 hold all 32 deposit entries, so their
+    /// account size doesn't change.
+    pub fn migrate_voter(ctx: Context<MigrateVoter>) -> Result<()> {
+        let mut data = ctx.accounts.voter.try_borrow_mut_data()?;
+        let mut voter = Voter::from_any_version(&data)?;
+        voter.migrate()?;
+        let mut dst: &mut [u8] = &mut data;
+        voter.try_serialize(&mut dst)
+    }
+
+    /// Moves a voter into a new account with room for `capacity` deposit
+    /// entries, and closes the old one. Deposits keep their ids.
+    ///
+    /// Programs can't resize accounts on the runtime this targets, so the
+    /// voter moves to the address derived from its registrar, authority and
+    /// `capacity`.
+    #[access_control(registrar_is_migrated(&ctx.accounts.registrar))]
+    pub fn grow_voter(ctx: Context<GrowVoter>, voter_bump: u8, capacity: u8) -> Result<()> {
+        let voter = &ctx.accounts.voter;
+        require!(
+            capacity > voter.capacity && capacity <= VOTER_MAX_CAPACITY,
+            InvalidVoterCapacity
+        );
+        let new_voter = &mut ctx.accounts.new_voter;
+        ***new_voter = Voter {
+            voter_bump,
+            capacity,
+            ..***voter
+        };
+        Ok(())
+    }
+
+    /// Copies a voter created before accounts were versioned into a new
+    /// account of the migrated registrar, see `migrate_unversioned_registrar`,
+    /// and closes the unversioned voter. Only the voter's authority can call
+    /// this.
+    #[access_control(registrar_is_migrated(&ctx.accounts.registrar))]
+    pub fn migrate_unversioned_voter(
+        ctx: Context<MigrateUnversionedVoter>,
+        voter_bump: u8,
+        voter_weight_record_bump: u8,
+    ) -> Result<()> {
+        let unversioned_registrar =
+            Registrar::from_unversioned(&ctx.accounts.unversioned_registrar.try_borrow_data()?)?;
+        let unversioned =
+            Voter::from_unversioned(&ctx.accounts.unversioned_voter.try_borrow_data()?)?;
+        require!(
+            unversioned.authority == ctx.accounts.authority.key()
+                && unversioned.registrar == ctx.accounts.unversioned_registrar.key(),
+            InvalidUnversionedAccount
+        );
+
+        let registrar = ctx.accounts.registrar.load()?;
+        require!(
+            registrar.realm == unversioned_registrar.realm
+                && registrar.realm_governing_token_mint
+                    == unversioned_registrar.realm_governing_token_mint,
+            InvalidUnversionedAccount
+        );
+
+        let voter = &mut ctx.accounts.voter;
+        ***voter = unversioned;
+        voter.registrar = ctx.accounts.registrar.key();
+        voter.voter_bump = voter_bump;
+        voter.voter_weight_record_bump = voter_weight_record_bump;
+
+        let voter_weight_record = &mut ctx.accounts.voter_weight_record;
+        voter_weight_record.account_type = VoterWeightAccountType::VoterWeightRecord;
+        voter_weight_record.realm = registrar.realm;
+        voter_weight_record.governing_token_mint = registrar.realm_governing_token_mint;
+        voter_weight_record.governing_token_owner = ctx.accounts.authority.key();
+
+        // Close the unversioned voter, so that its deposits can't be migrated
+        // twice.
+        let unversioned_voter = ctx.accounts.unversioned_voter.to_account_info();
+        let authority = ctx.accounts.authority.to_account_info();
+        **authority.lamports.borrow_mut() = authority
+            .lamports()
+            .checked_add(unversioned_voter.lamports())
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        **unversioned_voter.lamports.borrow_mut() = 0;
+        unversioned_voter.try_borrow_mut_data()?.fill(0);
+        Ok(())
+    }
+
     /// Closes the voter account, allowing one to retrieve rent exemption SOL.
     /// Only accounts with no remaining deposits can be closed.
     pub fn close_voter(ctx: Context<CloseVoter>) -> Result<()> {
-        let voter = &ctx.accounts.voter.load()?;
-        let amount = voter.deposits.iter().fold(0u64, |sum, d| {
-            sum.checked_add(d.amount_deposited.checked_sub(d.amount_withdrawn).unwrap())
-                .unwrap()
-        });
+        let voter = &ctx.accounts.voter;
+        let amount = voter
+            .deposits
+            .iter()
+            .try_fold(0u64, |sum, d| -> Result<u64> {
+                sum.checked_add(d.amount_left()?)
+                    .ok_or_else(|| ErrorCode::ArithmeticOverflow.into())
+            })?;
         require!(amount == 0, VotingTokenNonZero);
         Ok(())
     }
@@ -0,0 +1,28 @@
+use crate::error::*;
+use anchor_lang::prelude::*;
+
+/// Magic bytes identifying a price feed account.
+pub const PRICE_FEED_MAGIC: u32 = 0x6772_7066;
+
+/// Price feed account layout read by oracle-backed exchange rates.
+///
+/// The layout is intentionally minimal so that any price publisher (or a
+/// mock account on localnet) can produce it: a magic number followed by the
+/// exchange rate, in the same units as `ExchangeRateEntry::rate`, and the
+/// slot at which it was published.
+#[derive(AnchorSerialize, AnchorDeserialize, Debug, Clone, Copy)]
+pub struct PriceFeed {
+    pub magic: u32,
+    pub rate: u64,
+    pub publish_slot: u64,
+}
+
+impl PriceFeed {
+    /// Deserializes a price feed from the given account data.
+    pub fn load(data: &[u8]) -> Result<Self> {
+        let feed =
+            PriceFeed::deserialize(&mut &data[..]).map_err(|_| ErrorCode::InvalidPriceFeed)?;
+        require!(feed.magic == PRICE_FEED_MAGIC, InvalidPriceFeed);
+        Ok(feed)
+    }
+}
@@ -0,0 +1,138 @@
+//! Helpers for building SPL governance proposal instructions that configure a
+//! registrar whose authority is a governance account.
+//!
+//! The results are meant to be passed to
+//! `spl_governance::instruction::insert_instruction`.
+
+use crate::account::ExchangeRateEntry;
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::instruction::Instruction;
+use anchor_lang::InstructionData;
+use anchor_spl::associated_token::get_associated_token_address;
+use spl_governance::state::proposal_instruction::InstructionData as ProposalInstruction;
+
+/// Wraps one of this program's instructions for execution by a governance
+/// account.
+pub fn proposal_instruction(
+    accounts: impl ToAccountMetas,
+    args: impl InstructionData,
+) -> ProposalInstruction {
+    Instruction {
+        program_id: crate::ID,
+        accounts: accounts.to_account_metas(None),
+        data: args.data(),
+    }
+    .into()
+}
+
+/// Builds a proposal instruction for any instruction taking the
+/// `UpdateRegistrar` accounts, e.g. `instruction::SetPauseFlags`.
+pub fn update_registrar(
+    registrar: Pubkey,
+    governance: Pubkey,
+    args: impl InstructionData,
+) -> ProposalInstruction {
+    proposal_instruction(
+        crate::accounts::UpdateRegistrar {
+            registrar,
+            authority: governance,
+        },
+        args,
+    )
+}
+
+/// Builds a proposal instruction adding an exchange rate for `deposit_mint`.
+///
+/// `payer` funds the new exchange vault, so it must sign the
+/// transaction executing the proposal. The governance program only signs
+/// for the governance account, and governance accounts hold data, so they
+/// can't pay for new accounts themselves. `payer` must therefore be a wallet
+/// that will send the `ExecuteInstruction` transaction once the proposal
+/// passes, and the proposal can't be executed by anyone else.
+pub fn create_exchange_rate(
+    registrar: Pubkey,
+    governance: Pubkey,
+    payer: Pubkey,
+    deposit_mint: Pubkey,
+    idx: u16,
+    er: ExchangeRateEntry,
+) -> ProposalInstruction {
+    proposal_instruction(
+        crate::accounts::CreateExchangeRate {
+            exchange_vault: get_associated_token_address(&registrar, &deposit_mint),
+            deposit_mint,
+            registrar,
+            authority: governance,
+            payer,
+            rent: sysvar::rent::ID,
+            token_program: anchor_spl::token::ID,
+            associated_token_program: anchor_spl::associated_token::ID,
+            system_program: System::id(),
+        },
+        crate::instruction::CreateExchangeRate { idx, er },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn create_exchange_rate_instruction() {
+        let (registrar, governance, payer, deposit_mint) = (
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        );
+        let er = ExchangeRateEntry {
+            mint: deposit_mint,
+            rate: 1,
+            ..ExchangeRateEntry::default()
+        };
+        let ix = create_exchange_rate(registrar, governance, payer, deposit_mint, 1, er);
+
+        assert_eq!(ix.program_id, crate::ID);
+        assert_eq!(
+            ix.data,
+            crate::instruction::CreateExchangeRate { idx: 1, er }.data()
+        );
+        assert_eq!(ix.accounts.len(), 9);
+        let meta = |pubkey: Pubkey| {
+            ix.accounts
+                .iter()
+                .find(|meta| meta.pubkey == pubkey)
+                .unwrap()
+        };
+        // The governance account signs when the governance program executes
+        // the proposal.
+        assert!(meta(governance).is_signer);
+        assert!(!meta(governance).is_writable);
+        assert!(meta(payer).is_signer);
+        assert!(meta(payer).is_writable);
+        assert!(meta(registrar).is_writable);
+        assert!(!meta(registrar).is_signer);
+        assert!(!meta(deposit_mint).is_signer);
+    }
+
+    #[test]
+    pub fn update_registrar_instruction() {
+        let (registrar, governance) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let ix = update_registrar(
+            registrar,
+            governance,
+            crate::instruction::SetLockupEndAlignment { period: 60 },
+        );
+
+        assert_eq!(ix.program_id, crate::ID);
+        assert_eq!(
+            ix.data,
+            crate::instruction::SetLockupEndAlignment { period: 60 }.data()
+        );
+        assert_eq!(ix.accounts.len(), 2);
+        assert_eq!(ix.accounts[0].pubkey, registrar);
+        assert!(ix.accounts[0].is_writable);
+        assert_eq!(ix.accounts[1].pubkey, governance);
+        assert!(ix.accounts[1].is_signer);
+    }
+}
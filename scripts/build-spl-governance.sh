@@ -0,0 +1,35 @@
+#!/usr/bin/env bash
+# Builds the SPL governance program the tests load at genesis, see Anchor.toml,
+# at the revision of spl-governance in Cargo.lock.
+set -euo pipefail
+
+cd "$(dirname "$0")/.."
+
+out=deps/spl_governance.so
+rev=$(grep -A2 '^name = "spl-governance"$' Cargo.lock |
+    sed -n 's/^source = "git+.*#\([0-9a-f]*\)"$/\1/p')
+if [ -z "$rev" ]; then
+    echo "spl-governance revision not found in Cargo.lock" >&2
+    exit 1
+fi
+
+# deps/ holds the revision the program was built at, to rebuild it when
+# Cargo.lock changes.
+if [ -f "$out" ] && [ "$(cat deps/spl_governance.rev 2>/dev/null)" = "$rev" ]; then
+    exit 0
+fi
+
+src=target/solana-program-library
+if [ ! -d "$src" ]; then
+    git clone https://github.com/solana-labs/solana-program-library "$src"
+fi
+git -C "$src" fetch origin "$rev"
+git -C "$src" checkout --detach "$rev"
+
+cargo build-bpf \
+    --manifest-path "$src/governance/program/Cargo.toml" \
+    --bpf-out-dir "$src/target/deploy"
+
+mkdir -p deps
+cp "$src/target/deploy/spl_governance.so" "$out"
+echo "$rev" > deps/spl_governance.rev
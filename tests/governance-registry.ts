@@ -8,6 +8,8 @@ import {
   Keypair,
   SystemProgram,
   SYSVAR_RENT_PUBKEY,
+  Transaction,
+  TransactionInstruction,
 } from "@solana/web3.js";
 import {
   Token,
@@ -16,6 +18,95 @@ import {
 } from "@solana/spl-token";
 import { GovernanceRegistry } from "../target/types/governance_registry";
 
+// Decodes a `VoterWeightRecord`. It's defined by the SPL governance program,
+// so it isn't part of the IDL.
+async function fetchVoterWeightRecord(
+  program: Program<GovernanceRegistry>,
+  address: PublicKey
+) {
+  const info = await program.provider.connection.getAccountInfo(address);
+  const data = info.data;
+  // Skip the account type, realm, governing token mint and owner.
+  let offset = 1 + 32 * 3;
+  const governingTokenOwner = new PublicKey(data.slice(offset - 32, offset));
+  const voterWeight = new BN(data.slice(offset, offset + 8), "le");
+  offset += 8;
+  let voterWeightExpiry = null;
+  if (data[offset++] === 1) {
+    voterWeightExpiry = new BN(data.slice(offset, offset + 8), "le");
+    offset += 8;
+  }
+  let weightAction = null;
+  if (data[offset++] === 1) {
+    weightAction = data[offset++];
+  }
+  let weightActionTarget = null;
+  if (data[offset++] === 1) {
+    weightActionTarget = new PublicKey(data.slice(offset, offset + 32));
+  }
+  return {
+    governingTokenOwner,
+    voterWeight,
+    voterWeightExpiry,
+    weightAction,
+    weightActionTarget,
+  };
+}
+
+// Decodes a `Voter`. Voters only store the deposit entries they have room
+// for, so they aren't part of the IDL.
+async function fetchVoter(
+  program: Program<GovernanceRegistry>,
+  address: PublicKey
+) {
+  const info = await program.provider.connection.getAccountInfo(address);
+  const data = info.data;
+  // Skip the discriminator, version, authority, registrar and bumps.
+  const capacity = data[8 + 1 + 32 * 2 + 2];
+  const lastUncappedWeight = new BN(
+    data.slice(8 + 1 + 32 * 2 + 2 + 1, 8 + 1 + 32 * 2 + 2 + 1 + 8),
+    "le"
+  );
+  const deposits = [];
+  for (let i = 0; i < capacity; i++) {
+    // Skip the header, capacity, last uncapped weight and reserved bytes.
+    const offset = 8 + 1 + 32 * 2 + 2 + 1 + 8 + 55 + i * 59;
+    deposits.push({
+      isUsed: data[offset] === 1,
+      rateIdx: data[offset + 1],
+      amountDeposited: new BN(data.slice(offset + 2, offset + 10), "le"),
+      amountWithdrawn: new BN(data.slice(offset + 10, offset + 18), "le"),
+    });
+  }
+  return {
+    version: data[8],
+    authority: new PublicKey(data.slice(9, 41)),
+    registrar: new PublicKey(data.slice(41, 73)),
+    capacity,
+    lastUncappedWeight,
+    deposits,
+  };
+}
+
+// Finds the current account of a voter, which moves when it's grown.
+async function findVoter(
+  program: Program<GovernanceRegistry>,
+  registrar: PublicKey,
+  authority: PublicKey
+) {
+  const accounts = await program.provider.connection.getProgramAccounts(
+    program.programId,
+    {
+      filters: [
+        { memcmp: { offset: 9, bytes: authority.toBase58() } },
+        { memcmp: { offset: 41, bytes: registrar.toBase58() } },
+      ],
+    }
+  );
+  assert.ok(accounts.length === 1);
+  return accounts[0].pubkey;
+}
+
 describe("voting-rights", () => {
   anchor.setProvider(anchor.Provider.env());
 
@@ -23,7 +114,12 @@ describe("voting-rights", () => {
     .GovernanceRegistry as Program<GovernanceRegistry>;
 
   // Initialized variables shared across tests.
-  const realm = Keypair.generate().publicKey;
+  //
+  // The SPL governance program is loaded at genesis, see Anchor.toml.
+  const governanceProgramId = new PublicKey(
+    "GovER5Lthms3bLBqWub97yVrMmEogzX7xNjdXpPPCVZw"
+  );
+  const realmName = "governance-registry-test";
   const votingMintDecimals = 6;
   const tokenProgram = TOKEN_PROGRAM_ID;
   const associatedTokenProgram = ASSOCIATED_TOKEN_PROGRAM_ID;
@@ -31,7 +127,8 @@ describe("voting-rights", () => {
   const systemProgram = SystemProgram.programId;
 
   // Uninitialized variables shared across tests.
-  let registrar: PublicKey,
+  let realm: PublicKey,
+    registrar: PublicKey,
     votingMintA: PublicKey,
     votingMintB: PublicKey,
     voter: PublicKey,
@@ -48,7 +145,7 @@ describe("voting-rights", () => {
     mintB: PublicKey,
     godA: PublicKey,
     godB: PublicKey,
-    realmCommunityMint: PublicKey;
+    realmGoverningTokenMint: PublicKey;
   let tokenAClient: Token,
     tokenBClient: Token,
     votingTokenClientA: Token,
@@ -72,12 +169,71 @@ describe("voting-rights", () => {
     mintB = _mintB;
     godA = _godA;
     godB = _godB;
-    realmCommunityMint = mintA;
+    realmGoverningTokenMint = mintA;
+  });
+
+  it("Creates a realm", async () => {
+    const [_realm] = await PublicKey.findProgramAddress(
+      [Buffer.from("governance"), Buffer.from(realmName)],
+      governanceProgramId
+    );
+    const [communityTokenHolding] = await PublicKey.findProgramAddress(
+      [
+        Buffer.from("governance"),
+        _realm.toBuffer(),
+        realmGoverningTokenMint.toBuffer(),
+      ],
+      governanceProgramId
+    );
+
+    // `GovernanceInstruction::CreateRealm` of spl-governance 2.1, without a
+    // council mint or voter weight addin, counting the full supply.
+    const minCommunityTokensToCreateGovernance = new BN(1);
+    const fullSupplyFraction = new BN(10_000_000_000);
+    const name = Buffer.from(realmName);
+    const nameLen = Buffer.alloc(4);
+    nameLen.writeUInt32LE(name.length);
+    const data = Buffer.concat([
+      Buffer.from([0]),
+      nameLen,
+      name,
+      Buffer.from([0]),
+      minCommunityTokensToCreateGovernance.toArrayLike(Buffer, "le", 8),
+      Buffer.from([0]),
+      fullSupplyFraction.toArrayLike(Buffer, "le", 8),
+      Buffer.from([0]),
+    ]);
+    const wallet = program.provider.wallet.publicKey;
+    const keys = [
+      { pubkey: _realm, isWritable: true, isSigner: false },
+      { pubkey: wallet, isWritable: false, isSigner: false },
+      { pubkey: realmGoverningTokenMint, isWritable: false, isSigner: false },
+      { pubkey: communityTokenHolding, isWritable: true, isSigner: false },
+      { pubkey: wallet, isWritable: true, isSigner: true },
+      { pubkey: systemProgram, isWritable: false, isSigner: false },
+      { pubkey: tokenProgram, isWritable: false, isSigner: false },
+      { pubkey: rent, isWritable: false, isSigner: false },
+    ];
+    await program.provider.send(
+      new Transaction().add(
+        new TransactionInstruction({
+          keys,
+          programId: governanceProgramId,
+          data,
+        })
+      )
+    );
+
+    realm = _realm;
+    const realmAccount = await program.provider.connection.getAccountInfo(
+      realm
+    );
+    assert.ok(realmAccount.owner.equals(governanceProgramId));
   });
 
   it("Creates PDAs", async () => {
     const [_registrar, _registrarBump] = await PublicKey.findProgramAddress(
-      [realm.toBuffer()],
+      [realm.toBuffer(), realmGoverningTokenMint.toBuffer()],
       program.programId
     );
     const [_votingMintA, _votingMintBumpA] = await PublicKey.findProgramAddress(
@@ -166,12 +322,36 @@ describe("voting-rights", () => {
     );
   });
 
+  it("Rejects a realm of another governance program", async () => {
+    await assert.rejects(
+      program.rpc.createRegistrar(6, registrarBump, { perMint: {} }, {
+        accounts: {
+          registrar,
+          realm,
+          governanceProgramId: Keypair.generate().publicKey,
+          realmGoverningTokenMint,
+          realmAuthority: program.provider.wallet.publicKey,
+          authority: program.provider.wallet.publicKey,
+          payer: program.provider.wallet.publicKey,
+          systemProgram,
+          tokenProgram,
+          rent,
+        },
+      }),
+      (err: any) =>
+        err.msg === "Realm doesn't belong to the SPL governance program"
+    );
+  });
+
   it("Initializes a registrar", async () => {
-    await program.rpc.createRegistrar(6, registrarBump, {
+    const votingTokenMode = { perMint: {} };
+    await program.rpc.createRegistrar(6, registrarBump, votingTokenMode, {
       accounts: {
         registrar,
         realm,
-        realmCommunityMint,
+        governanceProgramId,
+        realmGoverningTokenMint,
+        realmAuthority: program.provider.wallet.publicKey,
         authority: program.provider.wallet.publicKey,
         payer: program.provider.wallet.publicKey,
         systemProgram,
@@ -179,48 +359,140 @@ describe("voting-rights", () => {
         rent,
       },
     });
+
+    // Exchange rate B is boosted above 100%, which the default supply mode
+    // can't account for.
+    await program.rpc.setMaxVoteWeightMode(
+      { lockedSupply: {} },
+      {
+        accounts: {
+          registrar,
+          authority: program.provider.wallet.publicKey,
+        },
+      }
+    );
   });
 
   it("Adds an exchange rate A", async () => {
     const er = {
       mint: mintA,
       rate: new BN(1),
+      rateDenominator: new BN(1),
       decimals: 6,
+      maxLockupDays: new BN(2555),
+      maxBoostPct: new BN(100),
+      oracle: PublicKey.default,
+      minRate: new BN(0),
+      maxRate: new BN(0),
+      maxStalenessSlots: new BN(0),
+      lastUpdateSlot: new BN(0),
     };
     await program.rpc.createExchangeRate(0, er, {
       accounts: {
         exchangeVault: exchangeVaultA,
         depositMint: mintA,
-        votingMint: votingMintA,
         registrar,
         authority: program.provider.wallet.publicKey,
+        payer: program.provider.wallet.publicKey,
         rent,
         tokenProgram,
         associatedTokenProgram,
         systemProgram,
       },
     });
+    await program.rpc.createRateVotingMint({
+      accounts: {
+        votingMint: votingMintA,
+        depositMint: mintA,
+        registrar,
+        payer: program.provider.wallet.publicKey,
+        rent,
+        tokenProgram,
+        systemProgram,
+      },
+    });
   });
 
   it("Adds an exchange rate B", async () => {
     const er = {
       mint: mintB,
       rate: new BN(1000000),
+      rateDenominator: new BN(1),
       decimals: 0,
+      maxLockupDays: new BN(365),
+      maxBoostPct: new BN(300),
+      oracle: PublicKey.default,
+      minRate: new BN(0),
+      maxRate: new BN(0),
+      maxStalenessSlots: new BN(0),
+      lastUpdateSlot: new BN(0),
     };
     await program.rpc.createExchangeRate(1, er, {
       accounts: {
         exchangeVault: exchangeVaultB,
         depositMint: mintB,
-        votingMint: votingMintB,
         registrar,
         authority: program.provider.wallet.publicKey,
+        payer: program.provider.wallet.publicKey,
         rent,
         tokenProgram,
         associatedTokenProgram,
         systemProgram,
       },
     });
+    await program.rpc.createRateVotingMint({
+      accounts: {
+        votingMint: votingMintB,
+        depositMint: mintB,
+        registrar,
+        payer: program.provider.wallet.publicKey,
+        rent,
+        tokenProgram,
+        systemProgram,
+      },
+    });
+  });
+
+  it("Sets a proposal creation weight policy", async () => {
+    const action = { createProposal: {} };
+    const policy = {
+      minLockupDays: new BN(30),
+      excludedRates: 0b10,
+    };
+    await program.rpc.setWeightPolicy(action, policy, {
+      accounts: {
+        registrar,
+        authority: program.provider.wallet.publicKey,
+      },
+    });
+
+    const registrarAccount = await program.account.registrar.fetch(registrar);
+    // @ts-ignore
+    const p = registrarAccount.weightPolicies[3];
+    assert.ok(p.minLockupDays.toNumber() === 30);
+    assert.ok(p.excludedRates === 0b10);
+  });
+
+  it("Transfers the registrar authority and back", async () => {
+    const newAuthority = Keypair.generate();
+    await program.rpc.setRegistrarAuthority(newAuthority.publicKey, {
+      accounts: {
+        registrar,
+        authority: program.provider.wallet.publicKey,
+      },
+    });
+    await program.rpc.setRegistrarAuthority(program.provider.wallet.publicKey, {
+      accounts: {
+        registrar,
+        authority: newAuthority.publicKey,
+      },
+      signers: [newAuthority],
+    });
+
+    const registrarAccount = await program.account.registrar.fetch(registrar);
+    assert.ok(
+      registrarAccount.authority.equals(program.provider.wallet.publicKey)
+    );
   });
 
   it("Initializes a voter", async () => {
@@ -242,27 +514,30 @@ describe("voting-rights", () => {
   it("Deposits cliff locked A tokens", async () => {
     const amount = new BN(10);
     const kind = { cliff: {} };
-    const days = 1;
-    await program.rpc.createDeposit(kind, amount, days, {
+    const granularity = { days: {} };
+    const days = new BN(1);
+    await program.rpc.createDeposit(kind, granularity, amount, days, {
       accounts: {
         deposit: {
           voter,
           exchangeVault: exchangeVaultA,
           depositToken: godA,
-          votingToken,
           authority: program.provider.wallet.publicKey,
           registrar,
           depositMint: mintA,
-          votingMint: votingMintA,
           tokenProgram,
-          systemProgram,
           associatedTokenProgram,
+          systemProgram,
           rent,
         },
       },
+      remainingAccounts: [
+        { pubkey: votingMintA, isWritable: true, isSigner: false },
+        { pubkey: votingToken, isWritable: true, isSigner: false },
+      ],
     });
 
-    const voterAccount = await program.account.voter.fetch(voter);
+    const voterAccount = await fetchVoter(program, voter);
     const deposit = voterAccount.deposits[0];
     assert.ok(deposit.isUsed);
     assert.ok(deposit.amountDeposited.toNumber() === 10);
@@ -282,15 +557,17 @@ describe("voting-rights", () => {
         voter,
         exchangeVault: exchangeVaultA,
         withdrawMint: mintA,
-        votingToken,
-        votingMint,
         destination: godA,
         authority: program.provider.wallet.publicKey,
         tokenProgram,
       },
+      remainingAccounts: [
+        { pubkey: votingMintA, isWritable: true, isSigner: false },
+        { pubkey: votingToken, isWritable: true, isSigner: false },
+      ],
     });
 
-    const voterAccount = await program.account.voter.fetch(voter);
+    const voterAccount = await fetchVoter(program, voter);
     const deposit = voterAccount.deposits[0];
     assert.ok(deposit.isUsed);
     assert.ok(deposit.amount.toNumber() === 0);
@@ -302,44 +579,247 @@ describe("voting-rights", () => {
   */
 
   it("Deposits daily locked A tokens", async () => {
-    const amount = new BN(10);
+    const amount = new BN(5110);
     const kind = { daily: {} };
-    const days = 1;
-    await program.rpc.createDeposit(kind, amount, days, {
+    const granularity = { days: {} };
+    const days = new BN(1);
+    await program.rpc.createDeposit(kind, granularity, amount, days, {
       accounts: {
         deposit: {
           voter,
           exchangeVault: exchangeVaultA,
           depositToken: godA,
-          votingToken,
           authority: program.provider.wallet.publicKey,
           registrar,
           depositMint: mintA,
-          votingMint: votingMintA,
           tokenProgram,
-          systemProgram,
           associatedTokenProgram,
+          systemProgram,
           rent,
         },
       },
+      remainingAccounts: [
+        { pubkey: votingMintA, isWritable: true, isSigner: false },
+        { pubkey: votingToken, isWritable: true, isSigner: false },
+      ],
     });
 
-    const voterAccount = await program.account.voter.fetch(voter);
-    const deposit = voterAccount.deposits[0];
+    const voterAccount = await fetchVoter(program, voter);
+    const deposit = voterAccount.deposits[1];
     assert.ok(deposit.isUsed);
-    assert.ok(deposit.amountDeposited.toNumber() === 10);
+    assert.ok(deposit.amountDeposited.toNumber() === 5110);
     assert.ok(deposit.rateIdx === 0);
   });
 
+  it("Pauses and unpauses deposits", async () => {
+    const setPauseFlags = (deposits: boolean) =>
+      program.rpc.setPauseFlags(
+        { deposits, withdrawals: false, weightUpdates: false },
+        {
+          accounts: {
+            registrar,
+            authority: program.provider.wallet.publicKey,
+          },
+        }
+      );
+
+    await setPauseFlags(true);
+    await assert.rejects(
+      program.rpc.updateDeposit(0, new BN(1), {
+        accounts: {
+          voter,
+          exchangeVault: exchangeVaultA,
+          depositToken: godA,
+          authority: program.provider.wallet.publicKey,
+          registrar,
+          depositMint: mintA,
+          tokenProgram,
+          associatedTokenProgram,
+          systemProgram,
+          rent,
+        },
+        remainingAccounts: [
+          { pubkey: votingMintA, isWritable: true, isSigner: false },
+          { pubkey: votingToken, isWritable: true, isSigner: false },
+        ],
+      })
+    );
+    await setPauseFlags(false);
+  });
+
+  it("Rejects deposits of another mint", async () => {
+    await assert.rejects(
+      program.rpc.updateDeposit(0, new BN(1), {
+        accounts: {
+          voter,
+          exchangeVault: exchangeVaultB,
+          depositToken: godB,
+          authority: program.provider.wallet.publicKey,
+          registrar,
+          depositMint: mintB,
+          tokenProgram,
+          associatedTokenProgram,
+          systemProgram,
+          rent,
+        },
+        remainingAccounts: [
+          { pubkey: votingMintB, isWritable: true, isSigner: false },
+          { pubkey: votingToken, isWritable: true, isSigner: false },
+        ],
+      }),
+      (err: any) => err.msg === "Mint doesn't match the deposit's exchange rate"
+    );
+  });
+
   it("Updates a vote weight record", async () => {
-    await program.rpc.updateVoterWeightRecord({
+    const weightAction = { castVote: {} };
+    const weightActionTarget = Keypair.generate().publicKey;
+    await program.rpc.updateVoterWeightRecord(
+      weightAction,
+      weightActionTarget,
+      {
+        accounts: {
+          registrar,
+          voter,
+          voterWeightRecord,
+          systemProgram,
+        },
+      }
+    );
+
+    // The cliff deposit of 10 has no voting power left after rounding, and
+    // the daily deposit of 5110 locked for one of 2555 days has 2.
+    const record = await fetchVoterWeightRecord(program, voterWeightRecord);
+    const slot = await program.provider.connection.getSlot();
+    assert.ok(record.voterWeight.toNumber() === 2);
+    assert.ok(record.voterWeightExpiry.toNumber() <= slot);
+    assert.ok(record.weightAction === 0);
+    assert.ok(record.weightActionTarget.equals(weightActionTarget));
+
+    // No cap is set, so the uncapped weight is the same.
+    const voterAccount = await fetchVoter(program, voter);
+    assert.ok(voterAccount.lastUncappedWeight.toNumber() === 2);
+  });
+
+  it("Batch updates vote weight records", async () => {
+    await program.rpc.updateVoterWeightRecords({ castVote: {} }, null, {
+      accounts: {
+        registrar,
+      },
+      remainingAccounts: [
+        { pubkey: voter, isWritable: true, isSigner: false },
+        { pubkey: voterWeightRecord, isWritable: true, isSigner: false },
+      ],
+    });
+
+    // The record is rewritten for casting votes on any target.
+    const record = await fetchVoterWeightRecord(program, voterWeightRecord);
+    const slot = await program.provider.connection.getSlot();
+    assert.ok(
+      record.governingTokenOwner.equals(program.provider.wallet.publicKey)
+    );
+    assert.ok(record.voterWeight.toNumber() === 2);
+    assert.ok(record.voterWeightExpiry.toNumber() <= slot);
+    assert.ok(record.weightAction === 0);
+    assert.ok(record.weightActionTarget === null);
+  });
+
+  it("Rejects a voter weight record of another voter", async () => {
+    const otherAuthority = Keypair.generate();
+    await program.provider.connection.confirmTransaction(
+      await program.provider.connection.requestAirdrop(
+        otherAuthority.publicKey,
+        1_000_000_000
+      )
+    );
+    const [otherVoter, otherVoterBump] = await PublicKey.findProgramAddress(
+      [registrar.toBuffer(), otherAuthority.publicKey.toBuffer()],
+      program.programId
+    );
+    const [otherVoterWeightRecord, otherVoterWeightRecordBump] =
+      await PublicKey.findProgramAddress(
+        [
+          anchor.utils.bytes.utf8.encode("voter-weight-record"),
+          registrar.toBuffer(),
+          otherAuthority.publicKey.toBuffer(),
+        ],
+        program.programId
+      );
+    await program.rpc.createVoter(otherVoterBump, otherVoterWeightRecordBump, {
       accounts: {
+        voter: otherVoter,
+        voterWeightRecord: otherVoterWeightRecord,
         registrar,
+        authority: otherAuthority.publicKey,
+        payer: program.provider.wallet.publicKey,
+        systemProgram,
+        associatedTokenProgram,
+        tokenProgram,
+        rent,
+      },
+      signers: [otherAuthority],
+    });
+
+    await assert.rejects(
+      program.rpc.updateVoterWeightRecords({ castVote: {} }, null, {
+        accounts: {
+          registrar,
+        },
+        remainingAccounts: [
+          { pubkey: voter, isWritable: true, isSigner: false },
+          { pubkey: otherVoterWeightRecord, isWritable: true, isSigner: false },
+        ],
+      }),
+      (err: any) =>
+        err.msg === "Voter weight record doesn't belong to the voter"
+    );
+
+    // The other voter's record wasn't written.
+    const record = await fetchVoterWeightRecord(
+      program,
+      otherVoterWeightRecord
+    );
+    assert.ok(record.governingTokenOwner.equals(otherAuthority.publicKey));
+    assert.ok(record.voterWeight.toNumber() === 0);
+    assert.ok(record.voterWeightExpiry === null);
+  });
+
+  it("Grows a voter", async () => {
+    const capacity = 5;
+    const [newVoter, newVoterBump] = await PublicKey.findProgramAddress(
+      [
+        registrar.toBuffer(),
+        program.provider.wallet.publicKey.toBuffer(),
+        Buffer.from([capacity]),
+      ],
+      program.programId
+    );
+    await program.rpc.growVoter(newVoterBump, capacity, {
+      accounts: {
         voter,
-        voterWeightRecord,
+        newVoter,
+        registrar,
         authority: program.provider.wallet.publicKey,
+        payer: program.provider.wallet.publicKey,
         systemProgram,
+        rent,
       },
     });
+
+    const voterAccount = await fetchVoter(program, newVoter);
+    assert.ok(voterAccount.capacity === capacity);
+    assert.ok(voterAccount.deposits.length === capacity);
+    assert.ok(voterAccount.deposits[1].amountDeposited.toNumber() === 5110);
+    assert.ok(!voterAccount.deposits[4].isUsed);
+    assert.ok(
+      (await program.provider.connection.getAccountInfo(voter)) === null
+    );
+    const found = await findVoter(
+      program,
+      registrar,
+      program.provider.wallet.publicKey
+    );
+    assert.ok(found.equals(newVoter));
+    voter = newVoter;
   });
 });